@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Anything flicd (or something pretending to be flicd) sends us should come back as an event or an
+// error, never a panic. Events that do unmarshal should also survive a round trip.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((evt, _)) = flic::events::unmarshal(data) {
+        let again = flic::events::unmarshal(&flic::events::marshal(&evt));
+        assert_eq!(again.ok().map(|(evt, _)| evt), Some(evt));
+    }
+});
@@ -0,0 +1,161 @@
+//! An async counterpart to `Client`, built on tokio. Only available with the `async` feature.
+//!
+//! ```no_run
+//! # async fn run() -> flic::Result<()> {
+//! use flic::commands::GetInfo;
+//!
+//! let (client, mut events) = flic::AsyncClient::connect("localhost:5551").await?;
+//! client.send_command(GetInfo {}).await?;
+//! while let Some(evt) = events.next_event().await {
+//!     println!("{:?}", evt?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::commands;
+use crate::error::FlicError;
+use crate::events;
+use crate::framing;
+use crate::Result;
+
+// How much we ask the socket for at a time. Most events are much smaller than this.
+const READ_CHUNK: usize = 512;
+
+pub struct AsyncClient {
+    writer: Mutex<OwnedWriteHalf>,
+}
+
+/// The events sent by flicd, in order. The stream ends when flicd closes the connection.
+pub struct EventStream {
+    reader: OwnedReadHalf,
+    // Bytes read from the socket that don't make up a complete packet yet.
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl AsyncClient {
+    /// Connects to flicd, returning a client for sending commands and the stream of events it
+    /// sends back. The two halves can be used from different tasks.
+    pub async fn connect(host: &str) -> Result<(AsyncClient, EventStream)> {
+        let stream = TcpStream::connect(host).await?;
+        let (reader, writer) = stream.into_split();
+        Ok((
+            AsyncClient {
+                writer: Mutex::new(writer),
+            },
+            EventStream {
+                reader,
+                buf: Vec::new(),
+                done: false,
+            },
+        ))
+    }
+
+    pub async fn send_command<C>(&self, cmd: C) -> Result<()>
+    where
+        C: commands::Command,
+    {
+        let body = framing::encode(&cmd);
+
+        let mut stream = self.writer.lock().await;
+        stream.write_all(body.as_slice()).await?;
+        stream.flush().await?;
+
+        Ok(())
+    }
+}
+
+impl EventStream {
+    /// Waits for the next event, for callers that don't want to pull in a `StreamExt`.
+    pub async fn next_event(&mut self) -> Option<Result<events::Event>> {
+        future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Result<events::Event>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(frame) = framing::decode(&mut this.buf) {
+                let evt = events::unmarshal(&frame).map(|(evt, _)| evt);
+                return Poll::Ready(Some(evt));
+            }
+
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let mut chunk = [0u8; READ_CHUNK];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.reader).poll_read(cx, &mut read_buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(FlicError::FlicD(err))));
+                }
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                    this.done = true;
+                    if !this.buf.is_empty() {
+                        // flicd went away in the middle of a packet.
+                        return Poll::Ready(Some(Err(FlicError::BrokenStream)));
+                    }
+                }
+                Poll::Ready(Ok(())) => this.buf.extend_from_slice(read_buf.filled()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{AnyCommand, Opcode, Ping};
+    use crate::events::{Event, PingResponse};
+    use crate::testing::FakeFlicd;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn send_command_and_read_response() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::Ping, |cmd| match cmd {
+            AnyCommand::Ping(ping) => vec![Event::PingResponse(PingResponse {
+                ping_id: ping.ping_id,
+            })],
+            _ => vec![],
+        });
+
+        let (client, mut events) = AsyncClient::connect(&fake.addr()).await.unwrap();
+        client.send_command(Ping { ping_id: 42 }).await.unwrap();
+
+        let evt = events.next_event().await.unwrap().unwrap();
+        assert_eq!(evt, Event::PingResponse(PingResponse { ping_id: 42 }));
+    }
+
+    #[tokio::test]
+    async fn stream_ends_when_flicd_disconnects() {
+        let fake = FakeFlicd::start().unwrap();
+        let (client, mut events) = AsyncClient::connect(&fake.addr()).await.unwrap();
+
+        // Make sure the fake has accepted us before hanging up.
+        client.send_command(Ping { ping_id: 1 }).await.unwrap();
+        fake.wait_for_command(Opcode::Ping, Duration::from_secs(5))
+            .unwrap();
+        fake.disconnect();
+
+        assert!(events.next_event().await.is_none());
+    }
+}
@@ -1,13 +1,24 @@
 extern crate clap;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
+use flic::enums::{BdAddrType, ConnectionStatus, LatencyMode, ScanWizardResult};
 use flic::events::{self, Event};
-use flic::{commands, FlicError, Result};
+use flic::{commands, BdAddr, FlicError, Result, ScanWizardProgress};
 use rand::Rng;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, SystemTime};
 
+// How long to wait for flicd to answer a command before giving up.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a button we connect to may sit idle before flicd disconnects it, in seconds. 511 means never.
+const AUTO_DISCONNECT_TIME: u16 = 511;
+
 fn main() -> Result<()> {
     // Flic CLI
     let app_m = App::new("Flic CLI")
@@ -19,7 +30,7 @@ fn main() -> Result<()> {
                 .long("flicd_addr")
                 .value_name("ADDR")
                 .default_value("localhost:5551")
-                .help("host:port address of the flicd service")
+                .help("address of the flicd service, as host:port or unix:/path/to/socket")
                 .takes_value(true),
         )
         .subcommand(
@@ -32,6 +43,67 @@ fn main() -> Result<()> {
                         .default_value("5"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("pair")
+                .about("pairs a new button with flicd")
+                .after_help(
+                    "Exits with status 0 once the button is paired, or 1 on other errors. If \
+                     pairing fails, the exit status says why: 11 cancelled, 12 timed out, 13 \
+                     button is private, 14 bluetooth unavailable, 15 internet backend error, 16 \
+                     invalid data, 17 button belongs to another partner, 18 button already \
+                     connected to another device, 19 any other failure reported by flicd.",
+                )
+                .arg(
+                    Arg::with_name("pair-timeout")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .help("give up pairing after this many seconds")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info").about("prints flicd's bluetooth controller state"),
+        )
+        .subcommand(
+            SubCommand::with_name("buttons")
+                .about("prints the address, uuid, color and serial number of each verified button"),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("deletes buttons from flicd's list of verified buttons")
+                .arg(
+                    Arg::with_name("bd-addr")
+                        .required(true)
+                        .multiple(true)
+                        .help("the buttons to delete"),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .help("don't ask for confirmation"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("disconnect")
+                .about("disconnects buttons, removing their connection channels for all clients")
+                .arg(
+                    Arg::with_name("bd-addr")
+                        .required(true)
+                        .multiple(true)
+                        .help("the buttons to disconnect"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("battery")
+                .about("prints the last known battery level of buttons")
+                .arg(
+                    Arg::with_name("bd-addr")
+                        .multiple(true)
+                        .help("the buttons to check, all verified buttons if none are given"),
+                ),
+        )
+        .subcommand(watch_command())
         .subcommand(
             SubCommand::with_name("connect").arg(
                 Arg::with_name("button-id")
@@ -48,6 +120,13 @@ fn main() -> Result<()> {
 
     match app_m.subcommand() {
         ("list", Some(m)) => handle_list(client, m)?,
+        ("pair", Some(m)) => handle_pair(client, m)?,
+        ("info", Some(_)) => client.info()?,
+        ("buttons", Some(_)) => client.buttons()?,
+        ("delete", Some(m)) => handle_delete(client, m)?,
+        ("disconnect", Some(m)) => client.disconnect(&parse_bd_addrs(m)?.unwrap_or_default())?,
+        ("battery", Some(m)) => client.battery(parse_bd_addrs(m)?)?,
+        ("watch", Some(m)) => handle_watch(client, m)?,
         ("connect", Some(m)) => handle_connect(client, m)?,
         _ => {}
     }
@@ -78,6 +157,228 @@ fn handle_list(client: Client, m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+fn handle_pair(client: Client, m: &ArgMatches) -> Result<()> {
+    let timeout = match m.value_of("pair-timeout") {
+        Some(v) => match v.parse() {
+            Ok(v) => Some(Duration::from_secs(v)),
+            Err(err) => return Err(FlicError::from("failed to parse int", err)),
+        },
+        None => None,
+    };
+
+    println!("Looking for buttons. Press the button you want to pair, Ctrl-C to stop.");
+    match client.pair(timeout) {
+        Ok(bd_addr) => {
+            println!("Paired button {}.", bd_addr);
+            Ok(())
+        }
+        Err(FlicError::ScanWizard(result)) => {
+            eprintln!("Pairing failed: {}", describe_scan_wizard_result(result));
+            process::exit(pair_exit_status(result));
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// The exit status for a failed pairing, see the pair subcommand's help. Results newer than this
+// client all share one status, so they can't wrap around to 0 or collide with the others.
+fn pair_exit_status(result: ScanWizardResult) -> i32 {
+    match result {
+        ScanWizardResult::Unknown(_) => 19,
+        result => 10 + i32::from(u8::from(result)),
+    }
+}
+
+fn describe_scan_wizard_result(result: ScanWizardResult) -> &'static str {
+    match result {
+        ScanWizardResult::WizardSuccess => "success",
+        ScanWizardResult::WizardCancelledByUser => "cancelled",
+        ScanWizardResult::WizardFailedTimeout => "timed out waiting for the button",
+        ScanWizardResult::WizardButtonIsPrivate => {
+            "the button switched to private mode, hold it down for 7 seconds and try again"
+        }
+        ScanWizardResult::WizardBluetoothUnavailable => "the bluetooth controller is unavailable",
+        ScanWizardResult::WizardInternetBackendError => "couldn't reach the Flic backend",
+        ScanWizardResult::WizardInvalidData => "the button supplied invalid identity data",
+        ScanWizardResult::WizardButtonBelongsToOtherPartner => {
+            "the button belongs to another partner"
+        }
+        ScanWizardResult::WizardButtonAlreadyConnectedToOtherDevice => {
+            "the button is connected to another device, disconnect it there first"
+        }
+        ScanWizardResult::Unknown(_) => "unknown scan wizard result",
+    }
+}
+
+// The buttons given as arguments, if there were any.
+fn parse_bd_addrs(m: &ArgMatches) -> Result<Option<Vec<BdAddr>>> {
+    match m.values_of("bd-addr") {
+        Some(addrs) => Ok(Some(addrs.map(str::parse).collect::<Result<_>>()?)),
+        None => Ok(None),
+    }
+}
+
+fn handle_delete(client: Client, m: &ArgMatches) -> Result<()> {
+    // At least one button is required.
+    let buttons = parse_bd_addrs(m)?.unwrap();
+
+    if !m.is_present("yes") {
+        let names: Vec<String> = buttons.iter().map(BdAddr::to_string).collect();
+        print!(
+            "Delete {} from flicd? They'll have to be paired again. [y/N] ",
+            names.join(", ")
+        );
+        if let Err(err) = io::stdout().flush() {
+            return Err(FlicError::from("failed to write prompt", err));
+        }
+
+        let mut answer = String::new();
+        if let Err(err) = io::stdin().read_line(&mut answer) {
+            return Err(FlicError::from("failed to read answer", err));
+        }
+        if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+            println!("Not deleting anything.");
+            return Ok(());
+        }
+    }
+
+    client.delete(&buttons)
+}
+
+fn handle_watch(client: Client, m: &ArgMatches) -> Result<()> {
+    let buttons = parse_bd_addrs(m)?;
+
+    client.watch(buttons, watch_format(m))
+}
+
+fn timed_out(event: &str, bd_addr: &BdAddr) -> FlicError {
+    FlicError::Generic(format!(
+        "timed out waiting for flicd to send {} for {}",
+        event, bd_addr
+    ))
+}
+
+fn describe_battery_status(status: &events::BatteryStatus) -> String {
+    if status.battery_percentage < 0 {
+        return String::from("battery level unknown");
+    }
+
+    let age = match SystemTime::now().duration_since(status.timestamp) {
+        Ok(age) => age.as_secs(),
+        Err(_) => 0,
+    };
+    let ago = |n: u64, unit: &str| {
+        let plural = if n == 1 { "" } else { "s" };
+        format!("{} {}{} ago", n, unit, plural)
+    };
+    let when = match age {
+        0..=59 => String::from("just now"),
+        60..=3599 => ago(age / 60, "minute"),
+        3600..=86399 => ago(age / 3600, "hour"),
+        _ => ago(age / 86400, "day"),
+    };
+    format!(
+        "{}%, as of {} ({})",
+        status.battery_percentage,
+        humantime::format_rfc3339_seconds(status.timestamp),
+        when
+    )
+}
+
+// flicd leaves out the color and serial number of buttons it doesn't know them for.
+fn or_dash(s: &str) -> &str {
+    if s.is_empty() {
+        "-"
+    } else {
+        s
+    }
+}
+
+// Turns an event into the line watch prints for it.
+type Format = fn(&Event, &HashMap<u32, BdAddr>) -> String;
+
+// Describes an event on a single line. buttons maps the connection channels and battery listeners
+// we've created to the buttons they're for.
+fn describe_event(evt: &Event, buttons: &HashMap<u32, BdAddr>) -> String {
+    let button = evt
+        .conn_id()
+        .and_then(|conn_id| buttons.get(&conn_id))
+        .or_else(|| evt.bd_addr());
+    let button = match (evt, button) {
+        (Event::BatteryStatus(evt), _) => buttons.get(&evt.listener_id),
+        (_, button) => button,
+    };
+    let prefix = match button {
+        Some(bd_addr) => format!("{}: ", bd_addr),
+        None => String::new(),
+    };
+
+    let click = |click_type, was_queued, time_diff| {
+        if was_queued {
+            format!("{:?} (queued, {}s ago)", click_type, time_diff)
+        } else {
+            format!("{:?}", click_type)
+        }
+    };
+    let desc = match evt {
+        Event::ButtonUpOrDown(evt) => click(evt.click_type, evt.was_queued, evt.time_diff),
+        Event::ButtonClickOrHold(evt) => click(evt.click_type, evt.was_queued, evt.time_diff),
+        Event::ButtonSingleOrDoubleClick(evt) => {
+            click(evt.click_type, evt.was_queued, evt.time_diff)
+        }
+        Event::ButtonSingleOrDoubleClickOrHold(evt) => {
+            click(evt.click_type, evt.was_queued, evt.time_diff)
+        }
+        Event::ConnectionStatusChanged(evt) => {
+            format!("{:?} ({:?})", evt.connection_status, evt.disconnect_reason)
+        }
+        Event::ConnectionChannelRemoved(evt) => {
+            format!("connection channel removed ({:?})", evt.removed_reason)
+        }
+        Event::BatteryStatus(evt) => format!("battery at {}%", evt.battery_percentage),
+        Event::BluetoothControllerStateChange(evt) => {
+            format!("bluetooth controller {:?}", evt.state)
+        }
+        evt => format!("{:?}", evt),
+    };
+
+    format!("{}{}", prefix, desc)
+}
+
+// The watch subcommand. It only takes --json when the cli is built with the json feature, so
+// default builds don't advertise a flag they can't honor.
+fn watch_command<'a, 'b>() -> App<'a, 'b> {
+    let mut cmd = SubCommand::with_name("watch")
+        .about("connects to buttons and prints every event flicd sends")
+        .arg(
+            Arg::with_name("bd-addr")
+                .multiple(true)
+                .help("the buttons to connect to, all verified buttons if none are given"),
+        );
+    if cfg!(feature = "json") {
+        cmd = cmd.arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("print events as JSON, one per line"),
+        );
+    }
+    cmd
+}
+
+#[cfg(feature = "json")]
+fn watch_format(m: &ArgMatches) -> Format {
+    if m.is_present("json") {
+        |evt, _| serde_json::to_string(evt).expect("events always serialize")
+    } else {
+        describe_event
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn watch_format(_: &ArgMatches) -> Format {
+    describe_event
+}
+
 fn handle_connect(client: Client, m: &ArgMatches) -> Result<()> {
     // Button ID is required.
     client.connect(m.value_of("button-id").unwrap())?;
@@ -166,6 +467,271 @@ impl Client {
         Ok(buttons)
     }
 
+    fn pair(self, timeout: Option<Duration>) -> Result<BdAddr> {
+        let client = Arc::new(self.client);
+        let mut wizard = client.scan_wizard()?;
+        let scan_wizard_id = wizard.scan_wizard_id();
+
+        // Both Ctrl-C and the timeout cancel the wizard, and then we wait for flicd to tell us it
+        // has stopped, so we never leave a wizard running.
+        let c = Arc::clone(&client);
+        let cancel = move || {
+            let _ = c.send_command(commands::CancelScanWizard { scan_wizard_id });
+        };
+
+        let on_interrupt = cancel.clone();
+        if let Err(err) = ctrlc::set_handler(on_interrupt) {
+            return Err(FlicError::from("failed to set Ctrl-C handler", err));
+        }
+
+        let timed_out = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = timeout {
+            let timed_out = Arc::clone(&timed_out);
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                timed_out.store(true, Ordering::SeqCst);
+                cancel();
+            });
+        }
+
+        let res = wizard.wait(|step| match step {
+            ScanWizardProgress::FoundPrivateButton => {
+                println!("Found a private button. Hold it down for 7 seconds to make it public.")
+            }
+            ScanWizardProgress::FoundPublicButton { bd_addr, name } => {
+                println!("Found button {} ({}), connecting...", name, bd_addr)
+            }
+            ScanWizardProgress::ButtonConnected => println!("Connected, pairing..."),
+        });
+
+        match res {
+            Err(FlicError::ScanWizard(ScanWizardResult::WizardCancelledByUser))
+                if timed_out.load(Ordering::SeqCst) =>
+            {
+                Err(FlicError::ScanWizard(ScanWizardResult::WizardFailedTimeout))
+            }
+            res => res,
+        }
+    }
+
+    fn info(self) -> Result<()> {
+        let info = self.client.get_info()?;
+
+        let addr_type = match info.my_bd_addr_type {
+            BdAddrType::PublicBdAddrType => String::from("public"),
+            BdAddrType::RandomBdAddrType => String::from("random"),
+            BdAddrType::Unknown(v) => format!("unknown type {}", v),
+        };
+        let max_connected = match info.max_concurrently_connected_buttons {
+            -1 => String::from("unknown"),
+            n => n.to_string(),
+        };
+        println!(
+            "Bluetooth controller:       {:?}",
+            info.bluetooth_controller_state
+        );
+        println!(
+            "Address:                    {} ({})",
+            info.my_bd_addr, addr_type
+        );
+        println!(
+            "Pending connections:        {} of {}",
+            info.current_pending_connections, info.max_pending_connections
+        );
+        println!("Max connected buttons:      {}", max_connected);
+        println!(
+            "Space for new connections:  {}",
+            if info.currently_no_space_for_new_connection {
+                "no"
+            } else {
+                "yes"
+            }
+        );
+        println!("Verified buttons:           {}", info.nb_verified_buttons);
+
+        Ok(())
+    }
+
+    fn buttons(self) -> Result<()> {
+        let info = self.client.get_info()?;
+        if info.bd_addr_of_verified_buttons.is_empty() {
+            println!("No verified buttons.");
+            return Ok(());
+        }
+
+        println!(
+            "{:<17}  {:<36}  {:<10}  SERIAL NUMBER",
+            "ADDRESS", "UUID", "COLOR"
+        );
+        for bd_addr in &info.bd_addr_of_verified_buttons {
+            let button = self.client.get_button_info(bd_addr)?;
+            println!(
+                "{:<17}  {:<36}  {:<10}  {}",
+                button.bd_addr.to_string(),
+                button.uuid.to_string(),
+                or_dash(&button.color),
+                or_dash(&button.serial_number)
+            );
+        }
+
+        Ok(())
+    }
+
+    fn delete(self, buttons: &[BdAddr]) -> Result<()> {
+        for bd_addr in buttons {
+            let bd_addr = *bd_addr;
+            let evt = self.client.request(
+                commands::DeleteButton { bd_addr },
+                move |evt| matches!(evt, Event::ButtonDeleted(evt) if evt.bd_addr == bd_addr),
+                Some(RESPONSE_TIMEOUT),
+            )?;
+
+            match evt {
+                Some(_) => println!("Deleted {}.", bd_addr),
+                None => return Err(timed_out("ButtonDeleted", &bd_addr)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // ForceDisconnect doesn't say anything to clients without a connection channel for the button,
+    // so we make one to find out when flicd is done.
+    fn disconnect(self, buttons: &[BdAddr]) -> Result<()> {
+        for bd_addr in buttons {
+            let channel = match self.client.connect_with_timeout(
+                bd_addr,
+                LatencyMode::Normal,
+                AUTO_DISCONNECT_TIME,
+                Some(RESPONSE_TIMEOUT),
+            )? {
+                Some(channel) => channel,
+                None => return Err(timed_out("CreateConnectionChannelResponse", bd_addr)),
+            };
+            let was_connected = channel.connection_status() != ConnectionStatus::Disconnected;
+
+            let conn_id = channel.conn_id();
+            let evt = self.client.request(
+                commands::ForceDisconnect { bd_addr: *bd_addr },
+                move |evt| matches!(evt, Event::ConnectionChannelRemoved(evt) if evt.conn_id == conn_id),
+                Some(RESPONSE_TIMEOUT),
+            )?;
+
+            match evt {
+                Some(_) if was_connected => println!("Disconnected {}.", bd_addr),
+                Some(_) => println!(
+                    "{} wasn't connected, removed its connection channels.",
+                    bd_addr
+                ),
+                None => return Err(timed_out("ConnectionChannelRemoved", bd_addr)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // flicd sends the current battery status as soon as a listener is created, so we create one
+    // for each button just long enough to hear it.
+    fn battery(self, buttons: Option<Vec<BdAddr>>) -> Result<()> {
+        let buttons = match buttons {
+            Some(buttons) => buttons,
+            None => self.client.get_info()?.bd_addr_of_verified_buttons,
+        };
+        if buttons.is_empty() {
+            println!("No verified buttons.");
+            return Ok(());
+        }
+
+        for bd_addr in &buttons {
+            let listener_id = rand::thread_rng().gen::<u32>();
+            let evt = self.client.request(
+                commands::CreateBatteryStatusListener {
+                    listener_id,
+                    bd_addr: *bd_addr,
+                },
+                move |evt| matches!(evt, Event::BatteryStatus(evt) if evt.listener_id == listener_id),
+                Some(RESPONSE_TIMEOUT),
+            );
+            self.client
+                .send_command(commands::RemoveBatteryStatusListener { listener_id })?;
+
+            match evt? {
+                Some(Event::BatteryStatus(status)) => {
+                    println!("{}: {}", bd_addr, describe_battery_status(&status))
+                }
+                _ => return Err(timed_out("BatteryStatus", bd_addr)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Connects to the given buttons, or every verified one, and prints each event with format
+    // until Ctrl-C, which removes everything we created from flicd.
+    fn watch(self, buttons: Option<Vec<BdAddr>>, format: Format) -> Result<()> {
+        let client = Arc::new(self.client);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (c, s) = (Arc::clone(&client), Arc::clone(&stopped));
+        if let Err(err) = ctrlc::set_handler(move || {
+            s.store(true, Ordering::SeqCst);
+            let _ = c.clean_up();
+            let _ = c.close();
+        }) {
+            return Err(FlicError::from("failed to set Ctrl-C handler", err));
+        }
+
+        let buttons = match buttons {
+            Some(buttons) => buttons,
+            None => client.get_info()?.bd_addr_of_verified_buttons,
+        };
+        if buttons.is_empty() {
+            println!("No buttons to watch.");
+            return Ok(());
+        }
+
+        // Connection channels and battery listeners are looked up in the same map, so their ids
+        // mustn't collide.
+        let mut ids = HashMap::new();
+        let mut channels = Vec::new();
+        for bd_addr in &buttons {
+            match client.connect_with_timeout(
+                bd_addr,
+                LatencyMode::Normal,
+                AUTO_DISCONNECT_TIME,
+                Some(RESPONSE_TIMEOUT),
+            ) {
+                Ok(Some(channel)) => {
+                    ids.insert(channel.conn_id(), *bd_addr);
+                    channels.push(channel);
+                }
+                Ok(None) => eprintln!("Timed out connecting to {}", bd_addr),
+                Err(err) => eprintln!("Failed to connect to {}: {}", bd_addr, err),
+            }
+
+            let listener_id = loop {
+                let id = rand::thread_rng().gen::<u32>();
+                if !ids.contains_key(&id) {
+                    break id;
+                }
+            };
+            client.send_command(commands::CreateBatteryStatusListener {
+                listener_id,
+                bd_addr: *bd_addr,
+            })?;
+            ids.insert(listener_id, *bd_addr);
+        }
+
+        loop {
+            match client.next_event() {
+                Ok((evt, _)) => println!("{}", format(&evt, &ids)),
+                // Ctrl-C closes the connection, which is how we get out of here.
+                Err(_) if stopped.load(Ordering::SeqCst) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
     fn connect(&self, button_id: &str) -> Result<()> {
         println!("Connect invoked for button {:?}", button_id);
 
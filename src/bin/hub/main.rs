@@ -1,24 +1,173 @@
-use flic::commands::GetInfo;
-use flic::events::Opcode;
-use flic::Result;
-use std::sync::Arc;
-use std::{thread, time};
+use clap::{App, Arg};
+use flic::commands::CreateBatteryStatusListener;
+use flic::events::{BatteryStatus, NewVerifiedButton, Opcode};
+use flic::{Click, Client, FlicError, Manager, Registry, Result, Rules, WorkerPool};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Where the registry and rules live unless paths are given as the first and second arguments.
+const REGISTRY_PATH: &str = "flic-buttons.conf";
+const RULES_PATH: &str = "flic-rules.conf";
+
+// How long a button may sit idle before flicd disconnects it, in seconds. 511 means never.
+const AUTO_DISCONNECT_TIME: u16 = 511;
+
+// How long to wait for flicd to create a connection channel before moving on to the next button.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() -> Result<()> {
-    let manager = Arc::new(flic::Manager::new("localhost:5551")?);
+    let app_m = App::new("Flic hub")
+        .about("Runs rules for clicks on the buttons in a registry")
+        .arg(
+            Arg::with_name("flicd-address")
+                .long("flicd_addr")
+                .value_name("ADDR")
+                .default_value("localhost:5551")
+                .help("address of the flicd service, as host:port or unix:/path/to/socket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("registry")
+                .default_value(REGISTRY_PATH)
+                .help("where the registry of known buttons is kept"),
+        )
+        .arg(
+            Arg::with_name("rules")
+                .default_value(RULES_PATH)
+                .help("the rules to run for clicks"),
+        )
+        .get_matches();
+
+    // Unwrap is fine here because every argument has a default.
+    let registry_path = app_m.value_of("registry").unwrap();
+    let rules_path = app_m.value_of("rules").unwrap();
+    let registry = Arc::new(Mutex::new(Registry::open(registry_path)?));
+    let rules = Arc::new(Rules::open(rules_path)?);
+    // Rules can make slow HTTP requests, so keep them off the thread reading events.
+    let client = Client::new(app_m.value_of("flicd-address").unwrap())?;
+    let manager = Arc::new(Manager::with_worker_pool(client, WorkerPool::default()));
 
-    manager.register_handler(Opcode::GetInfoResponse, |evt| {
-        println!("Event: {:?}", evt);
-    });
+    // Remember newly paired buttons, so we connect to them the next time we start.
+    let r = Arc::clone(&registry);
+    manager
+        .on::<NewVerifiedButton>(move |evt| {
+            let mut registry = r.lock().unwrap();
+            registry.add(&evt.bd_addr);
+            save(&registry);
+        })
+        .detach();
+
+    let buttons: Vec<_> = registry
+        .lock()
+        .unwrap()
+        .buttons()
+        .iter()
+        .map(|b| (b.bd_addr, b.latency_mode))
+        .collect();
+
+    // Listener ids are only ever used for buttons we know about at startup.
+    let listeners: HashMap<u32, _> = (1..).zip(buttons.iter().map(|(b, _)| *b)).collect();
+    let r = Arc::clone(&registry);
+    let l = listeners.clone();
+    manager
+        .on::<BatteryStatus>(move |evt| {
+            if let Some(bd_addr) = l.get(&evt.listener_id) {
+                let mut registry = r.lock().unwrap();
+                registry.update_battery_level(bd_addr, evt.battery_percentage);
+                save(&registry);
+            }
+        })
+        .detach();
 
     let m = Arc::clone(&manager);
-    thread::spawn(move || {
-        m.start().unwrap();
-    });
+    let handle = thread::spawn(move || m.start());
+
+    // On Ctrl-C, remove everything we created from flicd and let the manager return.
+    let stop = manager.stop_handle();
+    if let Err(err) = ctrlc::set_handler(move || stop.stop_and_clean_up()) {
+        return Err(FlicError::from("failed to set Ctrl-C handler", err));
+    }
 
-    thread::sleep(time::Duration::from_millis(1000));
-    manager.client.send_command(GetInfo {})?;
-    thread::sleep(time::Duration::from_millis(1000));
+    let info = manager.client.get_info()?;
+    println!("Info: {:?}", info);
+
+    let mut channels = vec![];
+    for (bd_addr, latency_mode) in &buttons {
+        match manager.client.get_button_info(bd_addr) {
+            Ok(info) => registry.lock().unwrap().update_button_info(&info),
+            Err(err) => eprintln!("Failed to get info for {}: {}", bd_addr, err),
+        }
+
+        match manager.client.connect_with_timeout(
+            bd_addr,
+            *latency_mode,
+            AUTO_DISCONNECT_TIME,
+            Some(CONNECT_TIMEOUT),
+        ) {
+            Ok(Some(channel)) => channels.push(channel),
+            Ok(None) => eprintln!("Timed out connecting to {}", bd_addr),
+            Err(err) => eprintln!("Failed to connect to {}: {}", bd_addr, err),
+        }
+    }
+    for (listener_id, bd_addr) in listeners {
+        manager.client.send_command(CreateBatteryStatusListener {
+            listener_id,
+            bd_addr,
+        })?;
+    }
+    save(&registry.lock().unwrap());
+
+    // Run the rules for clicks on the buttons we're connected to, looking up nicknames as we go.
+    let conns: Arc<HashMap<u32, _>> = Arc::new(
+        channels
+            .iter()
+            .map(|c| (c.conn_id(), *c.bd_addr()))
+            .collect(),
+    );
+    for opcode in [
+        Opcode::ButtonUpOrDown,
+        Opcode::ButtonClickOrHold,
+        Opcode::ButtonSingleOrDoubleClickOrHold,
+    ] {
+        let (r, rules, conns) = (
+            Arc::clone(&registry),
+            Arc::clone(&rules),
+            Arc::clone(&conns),
+        );
+        manager
+            .register_handler(opcode, move |evt| {
+                let click = Click::from_event(evt, |conn_id| {
+                    let bd_addr = conns.get(&conn_id)?;
+                    let nickname = r.lock().unwrap().get(bd_addr)?.nickname.clone();
+                    Some((*bd_addr, nickname))
+                });
+                let click = match click {
+                    Some(click) => click,
+                    None => return,
+                };
+                for action in rules.matching(&click) {
+                    if let Err(err) = action.run(&click) {
+                        eprintln!("Action for {} failed: {}", click.bd_addr, err);
+                    }
+                }
+            })
+            .detach();
+    }
+
+    println!(
+        "Connected to {} of {} buttons",
+        channels.len(),
+        buttons.len()
+    );
+
+    handle.join().unwrap()
+}
 
-    Ok(())
+// Saving is best-effort: the in-memory registry is still right, and we'll try again next change.
+fn save(registry: &Registry) {
+    if let Err(err) = registry.save() {
+        eprintln!("Failed to save registry: {}", err);
+    }
 }
@@ -0,0 +1,388 @@
+// Capture files record the packets a Client sends and receives, with the time each one went past,
+// so that a session can be looked at or played back later.
+//
+// A capture starts with MAGIC, followed by one record per packet:
+//
+//   timestamp: u64 // Microseconds since the Unix epoch, little endian.
+//   direction: u8  // 0 for an event received from flicd, 1 for a command sent to it.
+//   packet         // The packet exactly as it was on the wire, length header included.
+
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::client::Client;
+use crate::framing::HEADER_LEN;
+
+// Identifies capture files, and the version of the format.
+const MAGIC: &[u8; 8] = b"FLICCAP1";
+
+/// Which way a captured packet was going.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    // An event from flicd.
+    Received = 0,
+
+    // A command to flicd.
+    Sent = 1,
+}
+
+/// A single packet in a capture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub time: SystemTime,
+    pub direction: Direction,
+    // The opcode followed by the body, without the length header. This is what events::unmarshal
+    // and commands::unmarshal take.
+    pub frame: Vec<u8>,
+}
+
+/// Writes a capture, see `Client::start_capture`.
+pub struct CaptureWriter {
+    out: Box<dyn Write + Send>,
+}
+
+impl CaptureWriter {
+    /// Starts a capture in `out`, e.g. a newly created file.
+    pub fn new<W: Write + Send + 'static>(mut out: W) -> io::Result<CaptureWriter> {
+        out.write_all(MAGIC)?;
+        Ok(CaptureWriter { out: Box::new(out) })
+    }
+
+    /// Creates (or truncates) the file at `path` and starts a capture in it.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<CaptureWriter> {
+        CaptureWriter::new(File::create(path)?)
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let micros = record
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+
+        // Written in one go, so a record is never split up by a failure half way through.
+        let mut buf = Vec::with_capacity(9 + HEADER_LEN + record.frame.len());
+        buf.extend_from_slice(&micros.to_le_bytes());
+        buf.push(record.direction as u8);
+        buf.extend_from_slice(&(record.frame.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&record.frame);
+        self.out.write_all(&buf)?;
+        self.out.flush()
+    }
+}
+
+/// Reads the records in a capture, in the order they were written.
+pub struct CaptureReader<R> {
+    inner: R,
+}
+
+impl CaptureReader<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CaptureReader<BufReader<File>>> {
+        CaptureReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Checks that `inner` starts like a capture, and gets ready to read its records.
+    pub fn new(mut inner: R) -> io::Result<CaptureReader<R>> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a flic capture"));
+        }
+
+        Ok(CaptureReader { inner })
+    }
+
+    /// Returns the next record, or `None` at the end of the capture.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut head = [0u8; 9 + HEADER_LEN];
+        // Like read_exact, retry reads that were interrupted before any data arrived.
+        loop {
+            match self.inner.read(&mut head[..1]) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        self.inner.read_exact(&mut head[1..])?;
+
+        let mut micros = [0u8; 8];
+        micros.copy_from_slice(&head[..8]);
+        let time = UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(micros));
+
+        let direction = match head[8] {
+            0 => Direction::Received,
+            1 => Direction::Sent,
+            d => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unknown direction {} in capture", d),
+                ))
+            }
+        };
+
+        let len = u16::from_le_bytes([head[9], head[10]]) as usize;
+        let mut frame = vec![0u8; len];
+        self.inner.read_exact(&mut frame)?;
+
+        Ok(Some(Record {
+            time,
+            direction,
+            frame,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        self.read_record().transpose()
+    }
+}
+
+/// Plays back the events in a capture as if they were coming from flicd, waiting between them as
+/// long as they were apart when they were recorded. Commands in the capture are skipped.
+///
+/// ```no_run
+/// use flic::{Manager, Replay};
+///
+/// let replay = Replay::open("buttons.cap").unwrap().speed(10.0);
+/// let manager = Manager::with_client(replay.into_client());
+/// ```
+pub struct Replay<R> {
+    records: CaptureReader<R>,
+    speed: f64,
+    // When the last event we played back was received.
+    last: Option<SystemTime>,
+    // What's left of the packet being played back.
+    pending: Vec<u8>,
+}
+
+impl Replay<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Replay<BufReader<File>>> {
+        Ok(Replay::new(CaptureReader::open(path)?))
+    }
+}
+
+impl<R: Read> Replay<R> {
+    pub fn new(records: CaptureReader<R>) -> Replay<R> {
+        Replay {
+            records,
+            speed: 1.0,
+            last: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Plays back `speed` times faster than the events were recorded, e.g. 2.0 for twice as fast.
+    /// `f64::INFINITY` plays everything back without waiting, as do zero and negative speeds.
+    pub fn speed(mut self, speed: f64) -> Replay<R> {
+        self.speed = speed;
+        self
+    }
+
+    /// Returns a client that reads its events from the capture. Commands sent to it go nowhere,
+    /// so requests that wait for a response won't get one unless it's in the capture.
+    pub fn into_client(self) -> Client
+    where
+        R: Send + 'static,
+    {
+        Client::from_parts(self, io::sink())
+    }
+
+    // Waits until it's time for an event received at `time`.
+    fn wait_for(&mut self, time: SystemTime) {
+        if let Some(last) = self.last.replace(time) {
+            let gap = time.duration_since(last).unwrap_or_default();
+            if self.speed > 0.0 && self.speed.is_finite() {
+                thread::sleep(gap.div_f64(self.speed));
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Replay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            let record = match self.records.read_record()? {
+                Some(record) => record,
+                None => return Ok(0),
+            };
+            if record.direction != Direction::Received {
+                continue;
+            }
+
+            self.wait_for(record.time);
+            self.pending = (record.frame.len() as u16).to_le_bytes().to_vec();
+            self.pending.extend_from_slice(&record.frame);
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{self, Ping};
+    use crate::events::{self, ButtonUpOrDown, Event, PingResponse};
+    use crate::testing::{button_down, FakeFlicd, SharedBuf, TIMEOUT};
+    use crate::Manager;
+    use std::io::Cursor;
+    use std::sync::{mpsc, Arc};
+    use std::time::Instant;
+
+    fn ping_frame(ping_id: u32) -> Vec<u8> {
+        crate::framing::encode(&Ping { ping_id })[HEADER_LEN..].to_vec()
+    }
+
+    #[test]
+    fn client_capture() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+        let buf = SharedBuf::default();
+        client.start_capture(CaptureWriter::new(buf.clone()).unwrap());
+
+        client.send_command(Ping { ping_id: 3 }).unwrap();
+        fake.send_event(&button_down(4)).unwrap();
+        assert_eq!(client.next_event().unwrap().0, button_down(4));
+        assert!(client.stop_capture().is_some());
+
+        // Nothing after stop_capture is recorded.
+        client.send_command(Ping { ping_id: 5 }).unwrap();
+
+        let data = buf.contents();
+        let records = CaptureReader::new(Cursor::new(data))
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(
+            commands::unmarshal(&records[0].frame).unwrap().0,
+            commands::AnyCommand::Ping(Ping { ping_id: 3 })
+        );
+        assert_eq!(records[1].direction, Direction::Received);
+        assert_eq!(records[1].frame, events::marshal(&button_down(4)));
+        assert!(records[0].time <= records[1].time);
+    }
+
+    #[test]
+    fn not_a_capture() {
+        match CaptureReader::new(Cursor::new(b"something else".to_vec())) {
+            Err(err) => assert_eq!(err.kind(), ErrorKind::InvalidData),
+            Ok(_) => panic!("read a capture that isn't one"),
+        }
+    }
+
+    // Fails every other read with ErrorKind::Interrupted, like a read cut short by a signal.
+    struct Interrupting<R> {
+        inner: R,
+        interrupt: bool,
+    }
+
+    impl<R: Read> Read for Interrupting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn interrupted_reads_are_retried() {
+        let buf = SharedBuf::default();
+        let mut capture = CaptureWriter::new(buf.clone()).unwrap();
+        for ping_id in 1..=2 {
+            capture
+                .write(&Record {
+                    time: UNIX_EPOCH,
+                    direction: Direction::Sent,
+                    frame: ping_frame(ping_id),
+                })
+                .unwrap();
+        }
+
+        let inner = Interrupting {
+            inner: Cursor::new(buf.contents()),
+            interrupt: false,
+        };
+        let frames: Vec<_> = CaptureReader::new(inner)
+            .unwrap()
+            .map(|record| record.unwrap().frame)
+            .collect();
+        assert_eq!(frames, vec![ping_frame(1), ping_frame(2)]);
+    }
+
+    #[test]
+    fn replay_to_manager() {
+        let buf = SharedBuf::default();
+        let mut capture = CaptureWriter::new(buf.clone()).unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let records = vec![
+            (0, Direction::Received, events::marshal(&button_down(1))),
+            (100, Direction::Sent, ping_frame(9)),
+            (200, Direction::Received, events::marshal(&button_down(2))),
+            (400, Direction::Received, events::marshal(&button_down(3))),
+        ];
+        for (ms, direction, frame) in records {
+            capture
+                .write(&Record {
+                    time: start + Duration::from_millis(ms),
+                    direction,
+                    frame,
+                })
+                .unwrap();
+        }
+
+        let data = buf.contents();
+        let replay = Replay::new(CaptureReader::new(Cursor::new(data)).unwrap()).speed(4.0);
+        let manager = Arc::new(Manager::with_client(replay.into_client()));
+        let (tx, rx) = mpsc::channel();
+        let _downs = manager.on::<ButtonUpOrDown>(move |evt| tx.send(evt.conn_id).unwrap());
+
+        let began = Instant::now();
+        let m = Arc::clone(&manager);
+        std::thread::spawn(move || m.start());
+
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(2));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(3));
+        // 400ms of events at four times the speed.
+        assert!(began.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn replay_without_waiting() {
+        let buf = SharedBuf::default();
+        let mut capture = CaptureWriter::new(buf.clone()).unwrap();
+        let evt = Event::PingResponse(PingResponse { ping_id: 1 });
+        for secs in &[0, 3600] {
+            capture
+                .write(&Record {
+                    time: UNIX_EPOCH + Duration::from_secs(1_600_000_000 + secs),
+                    direction: Direction::Received,
+                    frame: events::marshal(&evt),
+                })
+                .unwrap();
+        }
+
+        let data = buf.contents();
+        let replay = Replay::new(CaptureReader::new(Cursor::new(data)).unwrap());
+        let client = replay.speed(f64::INFINITY).into_client();
+        assert_eq!(client.next_event().unwrap().0, evt);
+        assert_eq!(client.next_event().unwrap().0, evt);
+        assert!(client.next_event().is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use std::sync::{Arc, Mutex};
+
+use crate::client::Client;
+use crate::commands;
+use crate::enums::{ConnectionStatus, DisconnectReason, LatencyMode, RemovedReason};
+use crate::BdAddr;
+use crate::Result;
+
+/// A connection channel to a single Flic button, created with `Client::connect`. The status is
+/// kept up to date from the events the client reads, so something (e.g. a `Manager`) has to be
+/// reading events for it to change. Dropping the channel removes it from flicd.
+pub struct ConnectionChannel<'a> {
+    client: &'a Client,
+    conn_id: u32,
+    bd_addr: BdAddr,
+    state: Arc<Mutex<ChannelState>>,
+}
+
+// What we know about a connection channel from the events flicd has sent for it.
+pub(crate) struct ChannelState {
+    pub(crate) connection_status: ConnectionStatus,
+    pub(crate) disconnect_reason: Option<DisconnectReason>,
+    pub(crate) removed_reason: Option<RemovedReason>,
+    // Set once flicd no longer knows about the channel, so there is nothing left to remove.
+    pub(crate) removed: bool,
+}
+
+impl ChannelState {
+    pub(crate) fn new() -> ChannelState {
+        ChannelState {
+            connection_status: ConnectionStatus::Disconnected,
+            disconnect_reason: None,
+            removed_reason: None,
+            removed: false,
+        }
+    }
+}
+
+impl<'a> ConnectionChannel<'a> {
+    pub(crate) fn new(
+        client: &'a Client,
+        conn_id: u32,
+        bd_addr: BdAddr,
+        state: Arc<Mutex<ChannelState>>,
+    ) -> ConnectionChannel<'a> {
+        ConnectionChannel {
+            client,
+            conn_id,
+            bd_addr,
+            state,
+        }
+    }
+
+    pub(crate) fn mark_removed(&self) {
+        self.state.lock().unwrap().removed = true;
+    }
+
+    pub fn conn_id(&self) -> u32 {
+        self.conn_id
+    }
+
+    pub fn bd_addr(&self) -> &BdAddr {
+        &self.bd_addr
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.state.lock().unwrap().connection_status
+    }
+
+    /// The reason the button last disconnected, if it ever has since the channel was created.
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.state.lock().unwrap().disconnect_reason
+    }
+
+    /// Why flicd removed the channel, if it has. A removed channel gets no further events.
+    pub fn removed_reason(&self) -> Option<RemovedReason> {
+        self.state.lock().unwrap().removed_reason
+    }
+
+    /// Changes the accepted latency for this channel, and the auto disconnect time, which applies
+    /// the next time the button connects.
+    pub fn change_mode(&self, latency_mode: LatencyMode, auto_disconnect_time: u16) -> Result<()> {
+        self.client.send_command(commands::ChangeModeParameters {
+            conn_id: self.conn_id,
+            latency_mode,
+            auto_disconnect_time,
+        })
+    }
+}
+
+impl<'a> Drop for ConnectionChannel<'a> {
+    fn drop(&mut self) {
+        self.client.forget_channel(self.conn_id);
+
+        if self.state.lock().unwrap().removed {
+            return;
+        }
+
+        // There's nobody to report a failure to, and if the connection is gone, so is the channel.
+        let _ = self.client.send_command(commands::RemoveConnectionChannel {
+            conn_id: self.conn_id,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{AnyCommand, Opcode};
+    use crate::enums::CreateConnectionChannelError;
+    use crate::events::{
+        ConnectionChannelRemoved, ConnectionStatusChanged, CreateConnectionChannelResponse, Event,
+    };
+    use crate::testing::{bd_addr, FakeFlicd, TIMEOUT};
+    use crate::FlicError;
+    use std::time::Duration;
+
+    fn accept_channels(fake: &FakeFlicd, error: CreateConnectionChannelError) {
+        fake.respond_to(Opcode::CreateConnectionChannel, move |cmd| match cmd {
+            AnyCommand::CreateConnectionChannel(cmd) => {
+                vec![Event::CreateConnectionChannelResponse(
+                    CreateConnectionChannelResponse {
+                        conn_id: cmd.conn_id,
+                        error,
+                        connection_status: ConnectionStatus::Disconnected,
+                    },
+                )]
+            }
+            _ => vec![],
+        });
+    }
+
+    fn status_changed(conn_id: u32, status: ConnectionStatus, reason: DisconnectReason) -> Event {
+        Event::ConnectionStatusChanged(ConnectionStatusChanged {
+            conn_id,
+            connection_status: status,
+            disconnect_reason: reason,
+        })
+    }
+
+    #[test]
+    fn tracks_connection_status() {
+        let fake = FakeFlicd::start().unwrap();
+        accept_channels(&fake, CreateConnectionChannelError::NoError);
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let channel = client
+            .connect(&bd_addr(), LatencyMode::Normal, 511)
+            .unwrap();
+        assert_eq!(channel.bd_addr(), &bd_addr());
+        assert_eq!(channel.connection_status(), ConnectionStatus::Disconnected);
+        assert_eq!(channel.disconnect_reason(), None);
+
+        let conn_id = channel.conn_id();
+        fake.send_event(&status_changed(
+            conn_id,
+            ConnectionStatus::Ready,
+            DisconnectReason::Unspecified,
+        ))
+        .unwrap();
+        fake.send_event(&status_changed(
+            conn_id + 1,
+            ConnectionStatus::Connected,
+            DisconnectReason::Unspecified,
+        ))
+        .unwrap();
+        client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(channel.connection_status(), ConnectionStatus::Ready);
+
+        fake.send_event(&status_changed(
+            conn_id,
+            ConnectionStatus::Disconnected,
+            DisconnectReason::TimedOut,
+        ))
+        .unwrap();
+        client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(channel.connection_status(), ConnectionStatus::Disconnected);
+        assert_eq!(
+            channel.disconnect_reason(),
+            Some(DisconnectReason::TimedOut)
+        );
+    }
+
+    #[test]
+    fn allocates_unique_ids() {
+        let fake = FakeFlicd::start().unwrap();
+        accept_channels(&fake, CreateConnectionChannelError::NoError);
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let first = client
+            .connect(&bd_addr(), LatencyMode::Normal, 511)
+            .unwrap();
+        let second = client
+            .connect(&bd_addr(), LatencyMode::Normal, 511)
+            .unwrap();
+        assert_ne!(first.conn_id(), second.conn_id());
+    }
+
+    #[test]
+    fn change_mode_and_remove_on_drop() {
+        let fake = FakeFlicd::start().unwrap();
+        accept_channels(&fake, CreateConnectionChannelError::NoError);
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let channel = client
+            .connect(&bd_addr(), LatencyMode::Normal, 511)
+            .unwrap();
+        let conn_id = channel.conn_id();
+        channel.change_mode(LatencyMode::Low, 60).unwrap();
+        drop(channel);
+
+        assert_eq!(
+            fake.wait_for_command(Opcode::RemoveConnectionChannel, TIMEOUT),
+            Some(AnyCommand::RemoveConnectionChannel(
+                commands::RemoveConnectionChannel { conn_id }
+            ))
+        );
+        assert_eq!(
+            fake.commands()[1],
+            AnyCommand::ChangeModeParameters(commands::ChangeModeParameters {
+                conn_id,
+                latency_mode: LatencyMode::Low,
+                auto_disconnect_time: 60,
+            })
+        );
+    }
+
+    #[test]
+    fn removed_by_flicd() {
+        let fake = FakeFlicd::start().unwrap();
+        accept_channels(&fake, CreateConnectionChannelError::NoError);
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let channel = client
+            .connect(&bd_addr(), LatencyMode::Normal, 511)
+            .unwrap();
+        fake.send_event(&Event::ConnectionChannelRemoved(ConnectionChannelRemoved {
+            conn_id: channel.conn_id(),
+            removed_reason: RemovedReason::DeletedByOtherClient,
+        }))
+        .unwrap();
+        client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            channel.removed_reason(),
+            Some(RemovedReason::DeletedByOtherClient)
+        );
+
+        // There's nothing left to remove, so dropping it sends nothing.
+        drop(channel);
+        assert!(fake
+            .wait_for_command(Opcode::RemoveConnectionChannel, Duration::from_millis(100))
+            .is_none());
+    }
+
+    #[test]
+    fn no_space_for_channel() {
+        let fake = FakeFlicd::start().unwrap();
+        accept_channels(
+            &fake,
+            CreateConnectionChannelError::MaxPendingConnectionsReached,
+        );
+        let client = Client::new(&fake.addr()).unwrap();
+
+        match client.connect(&bd_addr(), LatencyMode::Normal, 511) {
+            Err(FlicError::ConnectionChannel(
+                CreateConnectionChannelError::MaxPendingConnectionsReached,
+            )) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("expected an error"),
+        }
+        assert!(fake
+            .wait_for_command(Opcode::RemoveConnectionChannel, Duration::from_millis(100))
+            .is_none());
+    }
+
+    #[test]
+    fn connect_times_out() {
+        // Nothing answers the CreateConnectionChannel.
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let channel = client
+            .connect_with_timeout(
+                &bd_addr(),
+                LatencyMode::Normal,
+                511,
+                Some(Duration::from_millis(100)),
+            )
+            .unwrap();
+        assert!(channel.is_none());
+
+        // flicd may still create it, so it's removed again.
+        let conn_id = match fake.wait_for_command(Opcode::CreateConnectionChannel, TIMEOUT) {
+            Some(AnyCommand::CreateConnectionChannel(cmd)) => cmd.conn_id,
+            other => panic!("unexpected command {:?}", other),
+        };
+        assert_eq!(
+            fake.wait_for_command(Opcode::RemoveConnectionChannel, TIMEOUT),
+            Some(AnyCommand::RemoveConnectionChannel(
+                commands::RemoveConnectionChannel { conn_id }
+            ))
+        );
+    }
+}
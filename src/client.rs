@@ -1,26 +1,204 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{ErrorKind, Read, Write};
-use std::net::TcpStream;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::commands;
+use crate::capture::{CaptureWriter, Direction, Record};
+use crate::channel::{ChannelState, ConnectionChannel};
+use crate::commands::{self, AnyCommand, Kind};
+use crate::enums::{ConnectionStatus, CreateConnectionChannelError, LatencyMode};
 use crate::error::FlicError;
 use crate::events;
+use crate::framing::{self, FrameReader};
+use crate::scan_wizard::{ScanWizard, ScanWizardProgress};
+use crate::transport::{self, ReadHalf, Stream, WriteHalf};
+use crate::BdAddr;
 use crate::Result;
 
+// How long a thread waiting on a response sleeps before checking whether it should read from the
+// stream itself.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+type Matcher = Box<dyn Fn(&events::Event) -> bool + Send + 'static>;
+
 pub struct Client {
-    writer: Mutex<TcpStream>,
-    reader: Mutex<TcpStream>,
+    // Where to reconnect to. Clients created with from_parts don't know.
+    addr: Option<String>,
+    reconnect: Option<ReconnectPolicy>,
+    writer: Mutex<Box<dyn WriteHalf>>,
+    // Holds on to any part of a packet that arrived before a read timed out.
+    reader: Mutex<FrameReader<Box<dyn ReadHalf>>>,
+    // Events read by a thread waiting for a response that were meant for someone else. These are
+    // handed out by next_event before anything new is read from the stream.
+    backlog: Mutex<VecDeque<(events::Event, events::Opcode)>>,
+    waiters: Mutex<Vec<Waiter>>,
+    next_id: AtomicU32,
+    active: Mutex<Active>,
+    channel_addrs: Mutex<ChannelAddrs>,
+    // State for every ConnectionChannel handed out by connect that is still alive.
+    channels: Mutex<HashMap<u32, Arc<Mutex<ChannelState>>>>,
+    // Set by close, after which we don't try to reconnect.
+    closed: AtomicBool,
+    // A reconnect that a caller's timeout cut short, carried on by the next read. Only touched
+    // while holding reader.
+    reconnecting: Mutex<Option<Reconnecting>>,
+    // Where packets are recorded, between start_capture and stop_capture.
+    capture: Mutex<Option<CaptureWriter>>,
+}
+
+/// Controls how a `Client` created with `Client::with_reconnect` gets its connection back. The
+/// delay between attempts starts at `initial_backoff` and doubles after every failure, up to
+/// `max_backoff`.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // Give up after this many failed attempts in a row, or never if None.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+// The connection channels, scanners and battery status listeners this client has created and not
+// yet removed, keyed by id. Each maps to the packet that created it, so it can be sent again after
+// reconnecting.
+#[derive(Default)]
+struct Active {
+    connection_channels: BTreeMap<u32, Vec<u8>>,
+    scanners: BTreeMap<u32, Vec<u8>>,
+    battery_status_listeners: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Active {
+    fn get_mut(&mut self, kind: Kind) -> &mut BTreeMap<u32, Vec<u8>> {
+        match kind {
+            Kind::ConnectionChannel => &mut self.connection_channels,
+            Kind::Scanner => &mut self.scanners,
+            Kind::BatteryStatusListener => &mut self.battery_status_listeners,
+        }
+    }
+}
+
+// The button each connection channel this client created is for, so events that only carry a
+// conn_id can be matched to a button. A removed channel's entry outlives the event that removed it
+// until the caller comes back for the next event, so handlers for that event can still look it up.
+#[derive(Default)]
+struct ChannelAddrs {
+    addrs: HashMap<u32, BdAddr>,
+    // Channels whose removal has been handed out, to forget at the start of the next read.
+    retired: Vec<u32>,
+}
+
+// The connection channel an event says is gone, either removed or never created at all.
+fn ended_channel(evt: &events::Event) -> Option<u32> {
+    match evt {
+        events::Event::ConnectionChannelRemoved(evt) => Some(evt.conn_id),
+        events::Event::CreateConnectionChannelResponse(evt)
+            if evt.error != CreateConnectionChannelError::NoError =>
+        {
+            Some(evt.conn_id)
+        }
+        _ => None,
+    }
+}
+
+// What track_command changed, so it can be put back if the command never reached flicd.
+struct Undo {
+    kind: Kind,
+    id: u32,
+    created: bool,
+    previous: Option<Vec<u8>>,
+}
+
+// How far a reconnect has got: the delay to use after the next failure, when the next attempt is
+// due, and the error from the last one.
+struct Reconnecting {
+    backoff: Duration,
+    next_attempt: Instant,
+    attempts: u32,
+    last_err: FlicError,
+}
+
+// A pending request or other subscription. Events that match are sent to the waiter instead of
+// being returned from next_event. A waiter with once set is done after the first one.
+struct Waiter {
+    id: u32,
+    matches: Matcher,
+    once: bool,
+    tx: mpsc::Sender<events::Event>,
 }
 
 impl Client {
-    pub fn new(host: &str) -> Result<Client> {
-        let reader = TcpStream::connect(host)?;
+    /// Connects to flicd at `addr`, which is either a host:port for TCP, or a Unix domain socket
+    /// path prefixed with "unix:", e.g. "unix:/run/flicd.sock".
+    pub fn new(addr: &str) -> Result<Client> {
+        Client::open(addr, None)
+    }
+
+    /// Creates a client that survives flicd restarting or the connection dropping. Instead of
+    /// returning an error, `next_event` reconnects according to `policy`, re-creates every
+    /// connection channel, scanner and battery status listener that was active, and then returns
+    /// an `Event::Reconnected`. Commands sent while disconnected still fail, and pending requests
+    /// will not get their responses.
+    pub fn with_reconnect(addr: &str, policy: ReconnectPolicy) -> Result<Client> {
+        Client::open(addr, Some(policy))
+    }
+
+    /// Creates a client that talks to flicd over any reader and writer, e.g. in-memory pipes in
+    /// tests, a TLS stream, or a recorded capture. `reader` is read on a thread of its own, so it
+    /// doesn't need to support timeouts. Since the client doesn't know how to open the streams
+    /// again, it can't reconnect.
+    pub fn from_parts<R, W>(reader: R, writer: W) -> Client
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (reader, writer) = transport::split(reader, writer);
+        Client::with_halves(None, None, Box::new(reader), Box::new(writer))
+    }
+
+    fn open(addr: &str, reconnect: Option<ReconnectPolicy>) -> Result<Client> {
+        let reader = Stream::connect(addr)?;
         let writer = reader.try_clone()?;
-        Ok(Client {
+        Ok(Client::with_halves(
+            Some(String::from(addr)),
+            reconnect,
+            Box::new(reader),
+            Box::new(writer),
+        ))
+    }
+
+    fn with_halves(
+        addr: Option<String>,
+        reconnect: Option<ReconnectPolicy>,
+        reader: Box<dyn ReadHalf>,
+        writer: Box<dyn WriteHalf>,
+    ) -> Client {
+        Client {
+            addr,
+            reconnect,
             writer: Mutex::new(writer),
-            reader: Mutex::new(reader),
-        })
+            reader: Mutex::new(FrameReader::new(reader)),
+            backlog: Mutex::new(VecDeque::new()),
+            waiters: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(1),
+            active: Mutex::new(Active::default()),
+            channel_addrs: Mutex::new(ChannelAddrs::default()),
+            channels: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            reconnecting: Mutex::new(None),
+            capture: Mutex::new(None),
+        }
     }
 
     pub fn next_event(&self) -> Result<(events::Event, events::Opcode)> {
@@ -33,30 +211,38 @@ impl Client {
         &self,
         timeout: Option<Duration>,
     ) -> Result<Option<(events::Event, events::Opcode)>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
         let mut stream = self.reader.lock().unwrap();
-        stream.set_read_timeout(timeout)?;
-
-        let mut header = [0 as u8; 2];
-        match stream.read_exact(&mut header) {
-            Ok(_) => {}
-            Err(err) => match err.kind() {
-                ErrorKind::WouldBlock | ErrorKind::TimedOut => return Ok(None),
-                _ => return Err(FlicError::FlicD(err)),
-            },
-        }
 
-        let len = u16::from_le_bytes([header[0], header[1]]);
+        // The caller is done with the last event we handed out, so the channels it removed can
+        // be forgotten.
+        self.prune_channel_addrs();
 
-        let mut body = vec![0u8; len as usize];
-        match stream.read_exact(&mut body) {
-            Ok(_) => {}
-            Err(err) => match err.kind() {
-                ErrorKind::WouldBlock | ErrorKind::TimedOut => return Ok(None),
-                _ => return Err(FlicError::FlicD(err)),
-            },
-        }
+        loop {
+            // Check the backlog only once we hold the reader, so we can't miss an event that
+            // another thread read while we were waiting for the lock.
+            if let Some(evt) = self.backlog.lock().unwrap().pop_front() {
+                self.retire_channel_addr(&evt.0);
+                return Ok(Some(evt));
+            }
+
+            let timeout = match remaining(deadline) {
+                Some(Some(t)) => Some(t),
+                Some(None) => return Ok(None),
+                None => None,
+            };
+
+            let evt = match self.read_event(&mut stream, timeout)? {
+                Some(evt) => evt,
+                None => return Ok(None),
+            };
 
-        Ok(Some(events::unmarshal(&body)?))
+            // If a pending request claimed the event, keep reading.
+            if let Some(evt) = self.deliver(evt) {
+                self.retire_channel_addr(&evt.0);
+                return Ok(Some(evt));
+            }
+        }
     }
 
     pub fn send_command<C>(&self, cmd: C) -> Result<()>
@@ -65,21 +251,582 @@ impl Client {
     {
         let mut stream = self.writer.lock().unwrap();
 
-        let opcode = cmd.opcode();
-        let mut body = cmd.marshal();
-        // Prepend opcode
-        body.insert(0, opcode);
+        let body = framing::encode(&cmd);
+
+        // Track the command before sending it, otherwise flicd's answer (e.g. a
+        // ConnectionChannelRemoved) could be read before there's anything for it to undo.
+        let undo = self.track_command(&cmd, &body);
+        if let Err(err) = stream
+            .write_all(body.as_slice())
+            .and_then(|_| stream.flush())
+        {
+            if let Some(undo) = undo {
+                self.untrack_command(undo);
+            }
+            return Err(err.into());
+        }
+
+        self.record(Direction::Sent, &body[framing::HEADER_LEN..]);
+
+        Ok(())
+    }
+
+    /// Sends a command and waits for the first event accepted by `matches`, which is returned
+    /// instead of being handed to whoever is calling `next_event` (e.g. a `Manager`). Every other
+    /// event is left for them. If nobody else is reading from flicd, this call reads events
+    /// itself, holding on to the ones it doesn't want until the next call to `next_event`.
+    ///
+    /// Returns `Ok(None)` if the timeout elapses before a matching event arrives. A timeout of
+    /// `None` waits forever.
+    pub fn request<C, F>(
+        &self,
+        cmd: C,
+        matches: F,
+        timeout: Option<Duration>,
+    ) -> Result<Option<events::Event>>
+    where
+        C: commands::Command,
+        F: Fn(&events::Event) -> bool + Send + 'static,
+    {
+        let sub = self.subscribe(matches, true);
+        self.send_command(cmd)?;
+        sub.recv(timeout)
+    }
+
+    // Starts diverting events accepted by matches away from next_event, until the subscription is
+    // dropped or, if once is set, the first one arrives.
+    pub(crate) fn subscribe<F>(&self, matches: F, once: bool) -> Subscription<'_>
+    where
+        F: Fn(&events::Event) -> bool + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let id = self.next_id();
+        self.waiters.lock().unwrap().push(Waiter {
+            id,
+            matches: Box::new(matches),
+            once,
+            tx,
+        });
+
+        Subscription {
+            client: self,
+            id,
+            rx,
+        }
+    }
+
+    /// Sends a `GetInfo` command and waits for the `GetInfoResponse`.
+    pub fn get_info(&self) -> Result<events::GetInfoResponse> {
+        Ok(self
+            .get_info_with_timeout(None)?
+            .expect("call is blocking, response must be returned"))
+    }
+
+    pub fn get_info_with_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<events::GetInfoResponse>> {
+        let evt = self.request(
+            commands::GetInfo {},
+            |evt| matches!(evt, events::Event::GetInfoResponse(_)),
+            timeout,
+        )?;
+
+        Ok(evt.map(|evt| match evt {
+            events::Event::GetInfoResponse(resp) => resp,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Sends a `Ping` command with a fresh ping_id and waits for the `PingResponse` with the same
+    /// ping_id.
+    pub fn ping(&self) -> Result<events::PingResponse> {
+        Ok(self
+            .ping_with_timeout(None)?
+            .expect("call is blocking, response must be returned"))
+    }
+
+    pub fn ping_with_timeout(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<events::PingResponse>> {
+        let ping_id = self.next_id();
+        let evt = self.request(
+            commands::Ping { ping_id },
+            move |evt| match evt {
+                events::Event::PingResponse(resp) => resp.ping_id == ping_id,
+                _ => false,
+            },
+            timeout,
+        )?;
+
+        Ok(evt.map(|evt| match evt {
+            events::Event::PingResponse(resp) => resp,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Sends a `GetButtonInfo` command and waits for the `GetButtonInfoResponse` for the same
+    /// button.
+    pub fn get_button_info(&self, bd_addr: &BdAddr) -> Result<events::GetButtonInfoResponse> {
+        Ok(self
+            .get_button_info_with_timeout(bd_addr, None)?
+            .expect("call is blocking, response must be returned"))
+    }
+
+    pub fn get_button_info_with_timeout(
+        &self,
+        bd_addr: &BdAddr,
+        timeout: Option<Duration>,
+    ) -> Result<Option<events::GetButtonInfoResponse>> {
+        let want = *bd_addr;
+        let evt = self.request(
+            commands::GetButtonInfo { bd_addr: *bd_addr },
+            move |evt| match evt {
+                events::Event::GetButtonInfoResponse(resp) => resp.bd_addr == want,
+                _ => false,
+            },
+            timeout,
+        )?;
+
+        Ok(evt.map(|evt| match evt {
+            events::Event::GetButtonInfoResponse(resp) => resp,
+            _ => unreachable!(),
+        }))
+    }
+
+    /// Creates a connection channel to the button with a fresh conn_id, and waits for flicd to
+    /// accept it. Fails with `FlicError::ConnectionChannel` if flicd has no room for it. The
+    /// channel is removed when the returned handle is dropped.
+    pub fn connect(
+        &self,
+        bd_addr: &BdAddr,
+        latency_mode: LatencyMode,
+        auto_disconnect_time: u16,
+    ) -> Result<ConnectionChannel<'_>> {
+        Ok(self
+            .connect_with_timeout(bd_addr, latency_mode, auto_disconnect_time, None)?
+            .expect("call is blocking, channel must be returned"))
+    }
+
+    /// Like `connect`, but returns `Ok(None)` if flicd hasn't answered within the timeout, in which
+    /// case the channel is removed again.
+    pub fn connect_with_timeout(
+        &self,
+        bd_addr: &BdAddr,
+        latency_mode: LatencyMode,
+        auto_disconnect_time: u16,
+        timeout: Option<Duration>,
+    ) -> Result<Option<ConnectionChannel<'_>>> {
+        let conn_id = self.next_id();
+        let state = Arc::new(Mutex::new(ChannelState::new()));
+
+        // Register before sending, so we can't miss events for the channel.
+        self.channels
+            .lock()
+            .unwrap()
+            .insert(conn_id, Arc::clone(&state));
+        let channel = ConnectionChannel::new(self, conn_id, *bd_addr, state);
+
+        let evt = self.request(
+            commands::CreateConnectionChannel {
+                conn_id,
+                bd_addr: *bd_addr,
+                latency_mode,
+                auto_disconnect_time,
+            },
+            move |evt| match evt {
+                events::Event::CreateConnectionChannelResponse(resp) => resp.conn_id == conn_id,
+                _ => false,
+            },
+            timeout,
+        )?;
+
+        match evt {
+            Some(events::Event::CreateConnectionChannelResponse(resp))
+                if resp.error != CreateConnectionChannelError::NoError =>
+            {
+                // flicd never created it, so there's nothing to remove.
+                channel.mark_removed();
+                Err(FlicError::ConnectionChannel(resp.error))
+            }
+            Some(_) => Ok(Some(channel)),
+            // Dropping the channel removes it, in case flicd creates it after all.
+            None => Ok(None),
+        }
+    }
+
+    /// Starts a scan wizard with a fresh id, which finds, connects to and pairs with a new button.
+    pub fn scan_wizard(&self) -> Result<ScanWizard<'_>> {
+        ScanWizard::start(self, self.next_id())
+    }
+
+    /// Runs a scan wizard to completion, calling `progress` along the way, and returns the address
+    /// of the newly paired button.
+    pub fn pair<F>(&self, progress: F) -> Result<BdAddr>
+    where
+        F: FnMut(ScanWizardProgress),
+    {
+        self.scan_wizard()?.wait(progress)
+    }
+
+    // The address of the button behind a connection channel this client created, as long as the
+    // channel hasn't been removed.
+    pub(crate) fn channel_bd_addr(&self, conn_id: u32) -> Option<BdAddr> {
+        self.channel_addrs
+            .lock()
+            .unwrap()
+            .addrs
+            .get(&conn_id)
+            .copied()
+    }
+
+    fn prune_channel_addrs(&self) {
+        let mut channel_addrs = self.channel_addrs.lock().unwrap();
+        let ChannelAddrs { addrs, retired } = &mut *channel_addrs;
+        for conn_id in retired.drain(..) {
+            addrs.remove(&conn_id);
+        }
+    }
+
+    // Marks the channel an event ended, if any, to be forgotten at the start of the next read.
+    fn retire_channel_addr(&self, evt: &events::Event) {
+        if let Some(conn_id) = ended_channel(evt) {
+            self.channel_addrs.lock().unwrap().retired.push(conn_id);
+        }
+    }
 
-        // Get the length, and prepend that as the length header, little endian.
-        let len = body.len().to_le_bytes();
-        body.insert(0, len[1]);
-        body.insert(0, len[0]);
+    /// Removes every connection channel, scanner and battery status listener this client created
+    /// and hasn't removed yet, e.g. before shutting down.
+    pub fn clean_up(&self) -> Result<()> {
+        let (conn_ids, scan_ids, listener_ids): (Vec<u32>, Vec<u32>, Vec<u32>) = {
+            let active = self.active.lock().unwrap();
+            (
+                active.connection_channels.keys().copied().collect(),
+                active.scanners.keys().copied().collect(),
+                active.battery_status_listeners.keys().copied().collect(),
+            )
+        };
 
-        stream.write(body.as_slice())?;
-        stream.flush()?;
+        for conn_id in conn_ids {
+            self.send_command(commands::RemoveConnectionChannel { conn_id })?;
+            // Any ConnectionChannel for it has nothing left to remove when it's dropped.
+            if let Some(state) = self.channels.lock().unwrap().get(&conn_id) {
+                state.lock().unwrap().removed = true;
+            }
+        }
+        for scan_id in scan_ids {
+            self.send_command(commands::RemoveScanner { scan_id })?;
+        }
+        for listener_id in listener_ids {
+            self.send_command(commands::RemoveBatteryStatusListener { listener_id })?;
+        }
 
         Ok(())
     }
+
+    /// Starts recording every packet sent to and received from flicd in `capture`, replacing any
+    /// capture already in progress. See `Replay` for playing a capture back.
+    pub fn start_capture(&self, capture: CaptureWriter) {
+        *self.capture.lock().unwrap() = Some(capture);
+    }
+
+    /// Stops recording packets, returning the capture that was in progress.
+    pub fn stop_capture(&self) -> Option<CaptureWriter> {
+        self.capture.lock().unwrap().take()
+    }
+
+    // Adds a packet to the capture, if there is one.
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        let mut capture = self.capture.lock().unwrap();
+        if let Some(c) = capture.as_mut() {
+            let record = Record {
+                time: SystemTime::now(),
+                direction,
+                frame: frame.to_vec(),
+            };
+            // A broken capture shouldn't break the client, so we just stop recording.
+            if c.write(&record).is_err() {
+                *capture = None;
+            }
+        }
+    }
+
+    /// Closes the connection to flicd. Anyone waiting on an event gets an error, and a client
+    /// created with `with_reconnect` won't reconnect.
+    pub fn close(&self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        match self.writer.lock().unwrap().shutdown() {
+            // flicd already hung up.
+            Err(err) if err.kind() == ErrorKind::NotConnected => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    pub(crate) fn forget_channel(&self, conn_id: u32) {
+        self.channels.lock().unwrap().remove(&conn_id);
+    }
+
+    // Returns an id that hasn't been handed out by this client before, for use as a ping_id,
+    // conn_id, etc.
+    fn next_id(&self) -> u32 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // Reads the next event, reconnecting first if the connection was lost and this client has a
+    // reconnect policy.
+    fn read_event(
+        &self,
+        stream: &mut FrameReader<Box<dyn ReadHalf>>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(events::Event, events::Opcode)>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        let state = self.reconnecting.lock().unwrap().take();
+        let (policy, state) = match (&self.reconnect, state) {
+            // Carry on with the reconnect an earlier read timed out of.
+            (Some(policy), Some(state)) => (policy, state),
+            _ => {
+                let err = match read_frame(stream, timeout) {
+                    Ok(Some(frame)) => {
+                        self.record(Direction::Received, &frame);
+                        let evt = events::unmarshal(&frame)?;
+                        self.track_event(&evt.0);
+                        return Ok(Some(evt));
+                    }
+                    Ok(None) => return Ok(None),
+                    Err(err @ FlicError::FlicD(_)) | Err(err @ FlicError::BrokenStream) => err,
+                    Err(err) => return Err(err),
+                };
+
+                match &self.reconnect {
+                    Some(policy) if !self.closed.load(Ordering::SeqCst) => (
+                        policy,
+                        Reconnecting {
+                            backoff: policy.initial_backoff,
+                            next_attempt: Instant::now() + policy.initial_backoff,
+                            attempts: 0,
+                            last_err: err,
+                        },
+                    ),
+                    _ => return Err(err),
+                }
+            }
+        };
+
+        let attempts = match self.reconnect(stream, policy, state, deadline)? {
+            Some(attempts) => attempts,
+            None => return Ok(None),
+        };
+        Ok(Some((
+            events::Event::Reconnected(events::Reconnected { attempts }),
+            events::Opcode::Reconnected,
+        )))
+    }
+
+    // Connects to flicd again, backing off between attempts, and re-sends the commands that created
+    // everything that was active. Returns the number of attempts it took, or the last error if the
+    // policy gave up. If the deadline passes while waiting for the next attempt, returns None and
+    // leaves the state for the next read to carry on from.
+    fn reconnect(
+        &self,
+        stream: &mut FrameReader<Box<dyn ReadHalf>>,
+        policy: &ReconnectPolicy,
+        mut state: Reconnecting,
+        deadline: Option<Instant>,
+    ) -> Result<Option<u32>> {
+        let addr = match &self.addr {
+            Some(addr) => addr,
+            None => return Err(state.last_err),
+        };
+
+        let new_stream = loop {
+            if let Some(max) = policy.max_attempts {
+                if state.attempts >= max {
+                    return Err(state.last_err);
+                }
+            }
+
+            let wait = state.next_attempt.saturating_duration_since(Instant::now());
+            match remaining(deadline) {
+                Some(Some(left)) if left < wait => {
+                    thread::sleep(left);
+                    *self.reconnecting.lock().unwrap() = Some(state);
+                    return Ok(None);
+                }
+                Some(None) if !wait.is_zero() => {
+                    *self.reconnecting.lock().unwrap() = Some(state);
+                    return Ok(None);
+                }
+                _ => thread::sleep(wait),
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(state.last_err);
+            }
+            state.backoff = (state.backoff * 2).min(policy.max_backoff);
+            state.attempts += 1;
+
+            match Stream::connect(addr) {
+                Ok(s) => break s,
+                Err(err) => {
+                    state.last_err = FlicError::FlicD(err);
+                    state.next_attempt = Instant::now() + state.backoff;
+                }
+            }
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        *writer = Box::new(new_stream.try_clone()?);
+        *stream = FrameReader::new(Box::new(new_stream));
+
+        let active = self.active.lock().unwrap();
+        let packets = active
+            .connection_channels
+            .values()
+            .chain(active.scanners.values())
+            .chain(active.battery_status_listeners.values());
+        for packet in packets {
+            writer.write_all(packet)?;
+            self.record(Direction::Sent, &packet[framing::HEADER_LEN..]);
+        }
+        writer.flush()?;
+
+        Ok(Some(state.attempts))
+    }
+
+    // Keeps track of what this client has created, given a command that's about to be sent and
+    // its full packet.
+    fn track_command(&self, cmd: &dyn commands::Command, packet: &[u8]) -> Option<Undo> {
+        let (kind, id, created) = cmd.tracked()?;
+
+        let mut active = self.active.lock().unwrap();
+        let map = active.get_mut(kind);
+        let previous = if created {
+            map.insert(id, packet.to_vec())
+        } else {
+            map.remove(&id)
+        };
+        if let (Kind::ConnectionChannel, true) = (kind, created) {
+            self.set_channel_addr(id, packet);
+        }
+
+        Some(Undo {
+            kind,
+            id,
+            created,
+            previous,
+        })
+    }
+
+    fn untrack_command(&self, undo: Undo) {
+        if let (Kind::ConnectionChannel, true) = (undo.kind, undo.created) {
+            match &undo.previous {
+                Some(packet) => self.set_channel_addr(undo.id, packet),
+                None => {
+                    self.channel_addrs.lock().unwrap().addrs.remove(&undo.id);
+                }
+            }
+        }
+
+        let mut active = self.active.lock().unwrap();
+        let map = active.get_mut(undo.kind);
+        match undo.previous {
+            Some(packet) => map.insert(undo.id, packet),
+            None => map.remove(&undo.id),
+        };
+    }
+
+    // Remembers which button a channel is for, given the packet that creates it.
+    fn set_channel_addr(&self, conn_id: u32, packet: &[u8]) {
+        if let Ok((AnyCommand::CreateConnectionChannel(cmd), _)) =
+            commands::unmarshal(&packet[framing::HEADER_LEN..])
+        {
+            self.channel_addrs
+                .lock()
+                .unwrap()
+                .addrs
+                .insert(conn_id, cmd.bd_addr);
+        }
+    }
+
+    // Forgets about connection channels that flicd has removed, or never created at all, and keeps
+    // the state of live ConnectionChannels up to date.
+    fn track_event(&self, evt: &events::Event) {
+        self.update_channel(evt);
+
+        let conn_id = match ended_channel(evt) {
+            Some(conn_id) => conn_id,
+            None => return,
+        };
+
+        let mut active = self.active.lock().unwrap();
+        active.connection_channels.remove(&conn_id);
+    }
+
+    fn update_channel(&self, evt: &events::Event) {
+        let conn_id = match evt {
+            events::Event::CreateConnectionChannelResponse(evt) => evt.conn_id,
+            events::Event::ConnectionStatusChanged(evt) => evt.conn_id,
+            events::Event::ConnectionChannelRemoved(evt) => evt.conn_id,
+            _ => return,
+        };
+
+        let state = match self.channels.lock().unwrap().get(&conn_id) {
+            Some(state) => Arc::clone(state),
+            None => return,
+        };
+        let mut state = state.lock().unwrap();
+
+        match evt {
+            events::Event::CreateConnectionChannelResponse(evt) => {
+                state.connection_status = evt.connection_status;
+            }
+            events::Event::ConnectionStatusChanged(evt) => {
+                state.connection_status = evt.connection_status;
+                if evt.connection_status == ConnectionStatus::Disconnected {
+                    state.disconnect_reason = Some(evt.disconnect_reason);
+                }
+            }
+            events::Event::ConnectionChannelRemoved(evt) => {
+                state.connection_status = ConnectionStatus::Disconnected;
+                state.removed_reason = Some(evt.removed_reason);
+                state.removed = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Hands the event to the first pending request that wants it, or gives it back if there isn't
+    // one.
+    fn deliver(
+        &self,
+        evt: (events::Event, events::Opcode),
+    ) -> Option<(events::Event, events::Opcode)> {
+        let mut waiters = self.waiters.lock().unwrap();
+        let i = match waiters.iter().position(|w| (w.matches)(&evt.0)) {
+            Some(i) => i,
+            None => return Some(evt),
+        };
+
+        // A request that claims the event has no use for the channel's address afterwards.
+        let ended = ended_channel(&evt.0);
+        match waiters[i].tx.send(evt.0) {
+            Ok(()) => {
+                if waiters[i].once {
+                    waiters.remove(i);
+                }
+                if let Some(conn_id) = ended {
+                    self.channel_addrs.lock().unwrap().retired.push(conn_id);
+                }
+                None
+            }
+            // The request gave up before its event arrived, so nobody has claimed it.
+            Err(mpsc::SendError(e)) => {
+                waiters.remove(i);
+                Some((e, evt.1))
+            }
+        }
+    }
 }
 
 impl Iterator for Client {
@@ -89,3 +836,675 @@ impl Iterator for Client {
         Some(self.next_event())
     }
 }
+
+// The receiving end of a waiter. The waiter is removed when this is dropped, whether or not it got
+// its events.
+pub(crate) struct Subscription<'a> {
+    client: &'a Client,
+    id: u32,
+    rx: mpsc::Receiver<events::Event>,
+}
+
+impl<'a> Subscription<'a> {
+    // Waits for the next event for this subscription. If nobody else is reading from flicd, reads
+    // events itself, putting the ones it doesn't want in the backlog. Returns Ok(None) if the
+    // timeout elapses first.
+    pub(crate) fn recv(&self, timeout: Option<Duration>) -> Result<Option<events::Event>> {
+        let client = self.client;
+        let deadline = timeout.map(|t| Instant::now() + t);
+
+        loop {
+            if let Ok(evt) = self.rx.try_recv() {
+                return Ok(Some(evt));
+            }
+
+            let poll = match remaining(deadline) {
+                Some(Some(t)) => t.min(POLL_INTERVAL),
+                Some(None) => return Ok(None),
+                None => POLL_INTERVAL,
+            };
+
+            let mut stream = match client.reader.try_lock() {
+                Ok(stream) => stream,
+                Err(TryLockError::WouldBlock) => {
+                    // Somebody else is reading, they'll hand us our event.
+                    if let Ok(evt) = self.rx.recv_timeout(poll) {
+                        return Ok(Some(evt));
+                    }
+                    continue;
+                }
+                Err(TryLockError::Poisoned(err)) => panic!("reader lock poisoned: {}", err),
+            };
+
+            // The previous reader may have delivered our event right before letting go.
+            if let Ok(evt) = self.rx.try_recv() {
+                return Ok(Some(evt));
+            }
+
+            if let Some(evt) = client.read_event(&mut stream, Some(poll))? {
+                if let Some(evt) = client.deliver(evt) {
+                    client.backlog.lock().unwrap().push_back(evt);
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Subscription<'a> {
+    fn drop(&mut self) {
+        let mut waiters = self.client.waiters.lock().unwrap();
+        waiters.retain(|w| w.id != self.id);
+    }
+}
+
+// Returns None if there is no deadline, Some(None) if it has passed, and otherwise the time left.
+fn remaining(deadline: Option<Instant>) -> Option<Option<Duration>> {
+    deadline.map(|d| {
+        let now = Instant::now();
+        if now >= d {
+            None
+        } else {
+            Some(d - now)
+        }
+    })
+}
+
+fn read_frame(
+    stream: &mut FrameReader<Box<dyn ReadHalf>>,
+    timeout: Option<Duration>,
+) -> Result<Option<Vec<u8>>> {
+    stream.get_mut().set_read_timeout(timeout)?;
+    stream.read_frame()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Command;
+    use crate::commands::{AnyCommand, Opcode};
+    use crate::enums::{
+        BdAddrType, BluetoothControllerState, ConnectionStatus, LatencyMode, RemovedReason,
+    };
+    use crate::error::UnmarshalError;
+    use crate::events::{Event, GetButtonInfoResponse, GetInfoResponse};
+    use crate::testing::{button_down, FakeFlicd, SharedBuf, TIMEOUT};
+    use crate::{Manager, Uuid};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn get_info_response() -> Event {
+        Event::GetInfoResponse(GetInfoResponse {
+            bluetooth_controller_state: BluetoothControllerState::Attached,
+            my_bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            my_bd_addr_type: BdAddrType::PublicBdAddrType,
+            max_pending_connections: 15,
+            max_concurrently_connected_buttons: -1,
+            current_pending_connections: 0,
+            currently_no_space_for_new_connection: false,
+            nb_verified_buttons: 0,
+            bd_addr_of_verified_buttons: vec![],
+        })
+    }
+
+    #[test]
+    fn get_info_leaves_other_events() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::GetInfo, |_| {
+            vec![button_down(7), get_info_response()]
+        });
+
+        let client = Client::new(&fake.addr()).unwrap();
+        let info = client.get_info_with_timeout(Some(TIMEOUT)).unwrap();
+        assert_eq!(info.map(Event::GetInfoResponse), Some(get_info_response()));
+
+        // The button event that arrived first is still there for the next reader.
+        let (evt, _) = client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(evt, button_down(7));
+    }
+
+    #[test]
+    fn ping_matches_ping_id() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::Ping, |cmd| match cmd {
+            AnyCommand::Ping(ping) => vec![
+                Event::PingResponse(events::PingResponse {
+                    ping_id: ping.ping_id + 100,
+                }),
+                Event::PingResponse(events::PingResponse {
+                    ping_id: ping.ping_id,
+                }),
+            ],
+            _ => vec![],
+        });
+
+        let client = Client::new(&fake.addr()).unwrap();
+        let first = client.ping_with_timeout(Some(TIMEOUT)).unwrap().unwrap();
+        let second = client.ping_with_timeout(Some(TIMEOUT)).unwrap().unwrap();
+        assert_ne!(first.ping_id, second.ping_id);
+
+        // The stray response to the first ping went to the backlog.
+        let (evt, _) = client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            evt,
+            Event::PingResponse(events::PingResponse {
+                ping_id: first.ping_id + 100,
+            })
+        );
+    }
+
+    #[test]
+    fn get_button_info_matches_bd_addr() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::GetButtonInfo, |cmd| match cmd {
+            AnyCommand::GetButtonInfo(req) => vec![
+                Event::GetButtonInfoResponse(GetButtonInfoResponse {
+                    bd_addr: BdAddr([0x06, 0x05, 0x04, 0x03, 0x02, 0x01]),
+                    uuid: Uuid([0; 16]),
+                    color: String::new(),
+                    serial_number: String::new(),
+                }),
+                Event::GetButtonInfoResponse(GetButtonInfoResponse {
+                    bd_addr: req.bd_addr,
+                    uuid: Uuid([0x01; 16]),
+                    color: String::from("white"),
+                    serial_number: String::from("AB12-C34567"),
+                }),
+            ],
+            _ => vec![],
+        });
+
+        let client = Client::new(&fake.addr()).unwrap();
+        let bd_addr = BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let info = client
+            .get_button_info_with_timeout(&bd_addr, Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.bd_addr, bd_addr);
+        assert_eq!(info.color, "white");
+    }
+
+    #[test]
+    fn request_times_out() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let info = client
+            .get_info_with_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(info.is_none());
+    }
+
+    fn test_policy() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+            max_attempts: Some(50),
+        }
+    }
+
+    fn answer_pings(fake: &FakeFlicd) {
+        fake.respond_to(Opcode::Ping, |cmd| match cmd {
+            AnyCommand::Ping(ping) => vec![Event::PingResponse(events::PingResponse {
+                ping_id: ping.ping_id,
+            })],
+            _ => vec![],
+        });
+    }
+
+    fn count(fake: &FakeFlicd, opcode: Opcode) -> usize {
+        let opcode = opcode as u8;
+        fake.commands()
+            .iter()
+            .filter(|cmd| cmd.opcode() == opcode)
+            .count()
+    }
+
+    #[test]
+    fn reconnect_replays_active_state() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::with_reconnect(&fake.addr(), test_policy()).unwrap();
+
+        let bd_addr = BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        client
+            .send_command(commands::CreateConnectionChannel {
+                conn_id: 1,
+                bd_addr,
+                latency_mode: LatencyMode::Normal,
+                auto_disconnect_time: 511,
+            })
+            .unwrap();
+        client
+            .send_command(commands::CreateConnectionChannel {
+                conn_id: 2,
+                bd_addr,
+                latency_mode: LatencyMode::Low,
+                auto_disconnect_time: 511,
+            })
+            .unwrap();
+        client
+            .send_command(commands::RemoveConnectionChannel { conn_id: 2 })
+            .unwrap();
+        client
+            .send_command(commands::CreateScanner { scan_id: 3 })
+            .unwrap();
+        client
+            .send_command(commands::CreateBatteryStatusListener {
+                listener_id: 4,
+                bd_addr,
+            })
+            .unwrap();
+        client
+            .send_command(commands::CreateBatteryStatusListener {
+                listener_id: 5,
+                bd_addr,
+            })
+            .unwrap();
+
+        // Removing a connection channel doesn't touch a battery status listener with the same id.
+        fake.send_event(&Event::ConnectionChannelRemoved(
+            events::ConnectionChannelRemoved {
+                conn_id: 5,
+                removed_reason: RemovedReason::DeletedFromButton,
+            },
+        ))
+        .unwrap();
+        client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        fake.wait_for_command(Opcode::CreateBatteryStatusListener, TIMEOUT)
+            .unwrap();
+
+        fake.disconnect();
+        let (evt, opcode) = client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(opcode, events::Opcode::Reconnected);
+        match evt {
+            Event::Reconnected(evt) => assert!(evt.attempts >= 1),
+            _ => panic!("unexpected event {:?}", evt),
+        }
+
+        // Wait for the replayed listener, which is sent last.
+        let deadline = Instant::now() + TIMEOUT;
+        while count(&fake, Opcode::CreateBatteryStatusListener) < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let cmds = fake.commands();
+        let replayed = &cmds[6..];
+        assert_eq!(
+            replayed,
+            &[
+                AnyCommand::CreateConnectionChannel(commands::CreateConnectionChannel {
+                    conn_id: 1,
+                    bd_addr,
+                    latency_mode: LatencyMode::Normal,
+                    auto_disconnect_time: 511,
+                }),
+                AnyCommand::CreateScanner(commands::CreateScanner { scan_id: 3 }),
+                AnyCommand::CreateBatteryStatusListener(commands::CreateBatteryStatusListener {
+                    listener_id: 4,
+                    bd_addr,
+                }),
+                AnyCommand::CreateBatteryStatusListener(commands::CreateBatteryStatusListener {
+                    listener_id: 5,
+                    bd_addr,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect_forgets_removed_channels() {
+        let fake = FakeFlicd::start().unwrap();
+        answer_pings(&fake);
+        let client = Client::with_reconnect(&fake.addr(), test_policy()).unwrap();
+
+        for conn_id in 1..=2 {
+            client
+                .send_command(commands::CreateConnectionChannel {
+                    conn_id,
+                    bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                    latency_mode: LatencyMode::Normal,
+                    auto_disconnect_time: 511,
+                })
+                .unwrap();
+        }
+
+        fake.send_event(&Event::ConnectionChannelRemoved(
+            events::ConnectionChannelRemoved {
+                conn_id: 1,
+                removed_reason: RemovedReason::ForceDisconnectedByOtherClient,
+            },
+        ))
+        .unwrap();
+        fake.send_event(&Event::CreateConnectionChannelResponse(
+            events::CreateConnectionChannelResponse {
+                conn_id: 2,
+                error: CreateConnectionChannelError::MaxPendingConnectionsReached,
+                connection_status: ConnectionStatus::Disconnected,
+            },
+        ))
+        .unwrap();
+        for _ in 0..2 {
+            client
+                .next_event_with_timeout(Some(TIMEOUT))
+                .unwrap()
+                .unwrap();
+        }
+
+        fake.disconnect();
+        let (_, opcode) = client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(opcode, events::Opcode::Reconnected);
+
+        // Round trip a ping so we know anything replayed has arrived.
+        client.ping_with_timeout(Some(TIMEOUT)).unwrap().unwrap();
+        assert_eq!(count(&fake, Opcode::CreateConnectionChannel), 2);
+    }
+
+    #[test]
+    fn reconnect_gives_up() {
+        let fake = FakeFlicd::start().unwrap();
+        answer_pings(&fake);
+        let addr = fake.addr();
+        let client = Client::with_reconnect(
+            &addr,
+            ReconnectPolicy {
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+                max_attempts: Some(3),
+            },
+        )
+        .unwrap();
+        client.ping_with_timeout(Some(TIMEOUT)).unwrap().unwrap();
+
+        // Nothing is listening on the port anymore.
+        drop(fake);
+        match client.next_event_with_timeout(Some(TIMEOUT)) {
+            Err(FlicError::FlicD(_)) => {}
+            other => panic!("expected FlicD error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reconnect_backoff_respects_timeout() {
+        let fake = FakeFlicd::start().unwrap();
+        answer_pings(&fake);
+        let backoff = Duration::from_millis(300);
+        let client = Client::with_reconnect(
+            &fake.addr(),
+            ReconnectPolicy {
+                initial_backoff: backoff,
+                max_backoff: backoff,
+                max_attempts: Some(3),
+            },
+        )
+        .unwrap();
+        client.ping_with_timeout(Some(TIMEOUT)).unwrap().unwrap();
+
+        // The first attempt isn't due until the backoff is up, so this read times out first.
+        fake.disconnect();
+        let began = Instant::now();
+        let timeout = Duration::from_millis(200);
+        assert!(client
+            .next_event_with_timeout(Some(timeout))
+            .unwrap()
+            .is_none());
+        assert!(began.elapsed() < backoff);
+
+        // The next read carries on waiting for the same attempt rather than starting over.
+        match client.next_event_with_timeout(Some(TIMEOUT)).unwrap() {
+            Some((Event::Reconnected(evt), _)) => assert_eq!(evt.attempts, 1),
+            other => panic!("expected Reconnected, got {:?}", other),
+        }
+        assert!(began.elapsed() < timeout + backoff);
+    }
+
+    #[test]
+    fn close_does_not_reconnect() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::with_reconnect(&fake.addr(), test_policy()).unwrap();
+
+        client.close().unwrap();
+        match client.next_event_with_timeout(Some(TIMEOUT)) {
+            Err(FlicError::FlicD(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    // The read end of an in-memory pipe, which blocks until the test sends more data.
+    struct PipeReader(mpsc::Receiver<Vec<u8>>);
+
+    impl std::io::Read for PipeReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.recv() {
+                Ok(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    // Fails every write while the flag is set.
+    #[derive(Clone, Default)]
+    struct FlakyWriter(SharedBuf, Arc<AtomicBool>);
+
+    impl std::io::Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.1.load(Ordering::SeqCst) {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn packet(evt: &Event) -> Vec<u8> {
+        let frame = events::marshal(evt);
+        let mut packet = (frame.len() as u16).to_le_bytes().to_vec();
+        packet.extend_from_slice(&frame);
+        packet
+    }
+
+    #[test]
+    fn failed_send_isnt_tracked() {
+        let (_tx, rx) = mpsc::channel();
+        let writer = FlakyWriter::default();
+        let client = Client::from_parts(PipeReader(rx), writer.clone());
+        let create = |conn_id| commands::CreateConnectionChannel {
+            conn_id,
+            bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            latency_mode: LatencyMode::Normal,
+            auto_disconnect_time: 511,
+        };
+
+        // A channel that never reached flicd isn't removed by clean_up, and a removal that never
+        // reached flicd doesn't stop it from being removed.
+        writer.1.store(true, Ordering::SeqCst);
+        assert!(client.send_command(create(1)).is_err());
+        writer.1.store(false, Ordering::SeqCst);
+        client.send_command(create(2)).unwrap();
+        writer.1.store(true, Ordering::SeqCst);
+        assert!(client
+            .send_command(commands::RemoveConnectionChannel { conn_id: 2 })
+            .is_err());
+        writer.1.store(false, Ordering::SeqCst);
+        client.clean_up().unwrap();
+
+        let sent = writer.0.contents();
+        let mut reader = FrameReader::new(std::io::Cursor::new(sent));
+        let mut cmds = Vec::new();
+        while let Ok(Some(frame)) = reader.read_frame() {
+            cmds.push(commands::unmarshal(&frame).unwrap().0);
+        }
+        assert_eq!(
+            cmds,
+            vec![
+                AnyCommand::CreateConnectionChannel(create(2)),
+                AnyCommand::RemoveConnectionChannel(commands::RemoveConnectionChannel {
+                    conn_id: 2
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn from_parts_in_memory() {
+        let mut input = packet(&button_down(1));
+        input.extend(packet(&button_down(2)));
+        let output = SharedBuf::default();
+        let client = Client::from_parts(std::io::Cursor::new(input), output.clone());
+
+        assert_eq!(client.next_event().unwrap().0, button_down(1));
+        assert_eq!(client.next_event().unwrap().0, button_down(2));
+        match client.next_event() {
+            Err(FlicError::FlicD(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        client.send_command(commands::Ping { ping_id: 7 }).unwrap();
+        assert_eq!(
+            output.contents(),
+            framing::encode(&commands::Ping { ping_id: 7 })
+        );
+    }
+
+    #[test]
+    fn from_parts_timeout_and_close() {
+        let (tx, rx) = mpsc::channel();
+        let client = Arc::new(Client::from_parts(PipeReader(rx), SharedBuf::default()));
+
+        let data = packet(&button_down(3));
+        tx.send(data[..4].to_vec()).unwrap();
+        let got = client
+            .next_event_with_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        assert_eq!(got, None);
+        tx.send(data[4..].to_vec()).unwrap();
+        assert_eq!(client.next_event().unwrap().0, button_down(3));
+
+        // Closing wakes up a reader that's waiting with no timeout.
+        let c = Arc::clone(&client);
+        let reader = thread::spawn(move || c.next_event().map(|_| ()));
+        thread::sleep(Duration::from_millis(50));
+        client.close().unwrap();
+        match reader.join().unwrap() {
+            Err(FlicError::FlicD(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn partial_packet_survives_timeout() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let packet = packet(&button_down(3));
+
+        // Half the header, then the rest of the header and some of the body.
+        for part in &[&packet[..1], &packet[1..4]] {
+            fake.send_bytes(part).unwrap();
+            let got = client
+                .next_event_with_timeout(Some(Duration::from_millis(50)))
+                .unwrap();
+            assert_eq!(got, None);
+        }
+
+        fake.send_bytes(&packet[4..]).unwrap();
+        fake.send_event(&button_down(4)).unwrap();
+        assert_eq!(client.next_event().unwrap().0, button_down(3));
+        assert_eq!(client.next_event().unwrap().0, button_down(4));
+    }
+
+    #[test]
+    fn empty_packet_is_an_error() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        fake.send_frame(&[]).unwrap();
+        fake.send_event(&button_down(5)).unwrap();
+        match client.next_event() {
+            Err(FlicError::Unmarshal(UnmarshalError::EmptyPacket)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(client.next_event().unwrap().0, button_down(5));
+    }
+
+    #[test]
+    fn disconnect_mid_packet_breaks_stream() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        fake.send_bytes(&[0x05, 0x00, 0x04]).unwrap();
+        fake.disconnect();
+        for _ in 0..2 {
+            match client.next_event_with_timeout(Some(TIMEOUT)) {
+                Err(FlicError::BrokenStream) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn reconnect_after_broken_stream() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::with_reconnect(&fake.addr(), test_policy()).unwrap();
+
+        fake.send_bytes(&[0x05, 0x00, 0x04]).unwrap();
+        fake.disconnect();
+        match client.next_event_with_timeout(Some(TIMEOUT)).unwrap() {
+            Some((events::Event::Reconnected(_), _)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        fake.send_event(&button_down(6)).unwrap();
+        assert_eq!(client.next_event().unwrap().0, button_down(6));
+    }
+
+    #[test]
+    fn get_info_while_manager_is_reading() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::GetInfo, |_| {
+            vec![button_down(7), get_info_response()]
+        });
+
+        let manager = Arc::new(Manager::new(&fake.addr()).unwrap());
+        let (tx, rx) = mpsc::channel();
+        manager
+            .register_handler(events::Opcode::ButtonUpOrDown, move |evt| {
+                tx.send(format!("{:?}", evt)).unwrap();
+            })
+            .detach();
+        manager
+            .register_handler(events::Opcode::GetInfoResponse, |evt| {
+                panic!("response leaked to handler: {:?}", evt);
+            })
+            .detach();
+
+        let m = Arc::clone(&manager);
+        thread::spawn(move || m.start());
+
+        let info = manager.client.get_info_with_timeout(Some(TIMEOUT)).unwrap();
+        assert!(info.is_some());
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT),
+            Ok(format!("{:?}", button_down(7)))
+        );
+    }
+}
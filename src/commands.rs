@@ -1,15 +1,33 @@
 use std::fmt::{self, Formatter};
 
 use crate::enums::LatencyMode;
-use crate::BdAddr;
+use crate::error::{FlicError, UnmarshalError};
+use crate::events::{check_sz, load_bd_addr, load_u16, load_u32};
+use crate::{BdAddr, Result};
+use num::FromPrimitive;
 
 pub trait Command {
     fn marshal(&self) -> Vec<u8>;
     fn opcode(&self) -> u8;
+
+    /// What the command creates (true) or removes (false) on flicd, and its id, if it's something
+    /// a client keeps track of so it can be created again after reconnecting. Most commands leave
+    /// nothing behind.
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        None
+    }
+}
+
+/// The kinds of things a client can create on flicd and remove again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    ConnectionChannel,
+    Scanner,
+    BatteryStatusListener,
 }
 
 impl fmt::Debug for dyn Command {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+    fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), fmt::Error> {
         let v = hex::encode(self.marshal());
         f.write_fmt(format_args!(
             "Opcode {}, Body {}, Length {}",
@@ -20,8 +38,114 @@ impl fmt::Debug for dyn Command {
     }
 }
 
+#[derive(FromPrimitive, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    GetInfo = 0,
+    CreateScanner = 1,
+    RemoveScanner = 2,
+    CreateConnectionChannel = 3,
+    RemoveConnectionChannel = 4,
+    ForceDisconnect = 5,
+    ChangeModeParameters = 6,
+    Ping = 7,
+    GetButtonInfo = 8,
+    CreateScanWizard = 9,
+    CancelScanWizard = 10,
+    DeleteButton = 11,
+    CreateBatteryStatusListener = 12,
+    RemoveBatteryStatusListener = 13,
+}
+
+// Unmarshals a command as it appears on the wire, minus the length header: the opcode followed by
+// the body. This is what flicd does with the bytes a Client sends.
+pub fn unmarshal(data: &[u8]) -> Result<(AnyCommand, Opcode)> {
+    if data.is_empty() {
+        return Err(FlicError::Unmarshal(UnmarshalError::EmptyPacket));
+    }
+
+    let opcode = match FromPrimitive::from_u8(data[0]) {
+        Some(opcode) => opcode,
+        None => return Err(FlicError::Unmarshal(UnmarshalError::BadOpcode(data[0]))),
+    };
+
+    let unmarshal_command = match opcode {
+        Opcode::GetInfo => unmarshal_get_info,
+        Opcode::CreateScanner => unmarshal_create_scanner,
+        Opcode::RemoveScanner => unmarshal_remove_scanner,
+        Opcode::CreateConnectionChannel => unmarshal_create_connection_channel,
+        Opcode::RemoveConnectionChannel => unmarshal_remove_connection_channel,
+        Opcode::ForceDisconnect => unmarshal_force_disconnect,
+        Opcode::ChangeModeParameters => unmarshal_change_mode_parameters,
+        Opcode::Ping => unmarshal_ping,
+        Opcode::GetButtonInfo => unmarshal_get_button_info,
+        Opcode::CreateScanWizard => unmarshal_create_scan_wizard,
+        Opcode::CancelScanWizard => unmarshal_cancel_scan_wizard,
+        Opcode::DeleteButton => unmarshal_delete_button,
+        Opcode::CreateBatteryStatusListener => unmarshal_create_battery_status_listener,
+        Opcode::RemoveBatteryStatusListener => unmarshal_remove_battery_status_listener,
+    };
+
+    let cmd = unmarshal_command(&data[1..])?;
+
+    Ok((cmd, opcode))
+}
+
+// Any one of the commands above. Useful when the type of command isn't known until runtime, like
+// when reading commands off the wire.
+#[derive(Debug, PartialEq)]
+pub enum AnyCommand {
+    GetInfo(GetInfo),
+    CreateScanner(CreateScanner),
+    RemoveScanner(RemoveScanner),
+    CreateConnectionChannel(CreateConnectionChannel),
+    RemoveConnectionChannel(RemoveConnectionChannel),
+    ForceDisconnect(ForceDisconnect),
+    ChangeModeParameters(ChangeModeParameters),
+    Ping(Ping),
+    GetButtonInfo(GetButtonInfo),
+    CreateScanWizard(CreateScanWizard),
+    CancelScanWizard(CancelScanWizard),
+    DeleteButton(DeleteButton),
+    CreateBatteryStatusListener(CreateBatteryStatusListener),
+    RemoveBatteryStatusListener(RemoveBatteryStatusListener),
+}
+
+impl AnyCommand {
+    fn inner(&self) -> &dyn Command {
+        match self {
+            AnyCommand::GetInfo(cmd) => cmd,
+            AnyCommand::CreateScanner(cmd) => cmd,
+            AnyCommand::RemoveScanner(cmd) => cmd,
+            AnyCommand::CreateConnectionChannel(cmd) => cmd,
+            AnyCommand::RemoveConnectionChannel(cmd) => cmd,
+            AnyCommand::ForceDisconnect(cmd) => cmd,
+            AnyCommand::ChangeModeParameters(cmd) => cmd,
+            AnyCommand::Ping(cmd) => cmd,
+            AnyCommand::GetButtonInfo(cmd) => cmd,
+            AnyCommand::CreateScanWizard(cmd) => cmd,
+            AnyCommand::CancelScanWizard(cmd) => cmd,
+            AnyCommand::DeleteButton(cmd) => cmd,
+            AnyCommand::CreateBatteryStatusListener(cmd) => cmd,
+            AnyCommand::RemoveBatteryStatusListener(cmd) => cmd,
+        }
+    }
+}
+
+impl Command for AnyCommand {
+    fn marshal(&self) -> Vec<u8> {
+        self.inner().marshal()
+    }
+    fn opcode(&self) -> u8 {
+        self.inner().opcode()
+    }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        self.inner().tracked()
+    }
+}
+
 // This command is used to retrieve current state about the server. After this command is sent, an
 // EvtGetInfoResponse is sent back.
+#[derive(Debug, PartialEq)]
 pub struct GetInfo {}
 
 impl Command for GetInfo {
@@ -33,10 +157,17 @@ impl Command for GetInfo {
     }
 }
 
+fn unmarshal_get_info(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 0)?;
+
+    Ok(AnyCommand::GetInfo(GetInfo {}))
+}
+
 // Creates a scanner with the given scan_id. For each advertisement packet received from a Flic
 // button by the server, an EvtAdvertisementPacket will be sent with the given scan_id until it is
 // removed using CmdRemoveScanner. If there is already an active scanner with this scan_id, this
 // does nothing.
+#[derive(Debug, PartialEq)]
 pub struct CreateScanner {
     pub scan_id: u32,
 }
@@ -48,10 +179,22 @@ impl Command for CreateScanner {
     fn opcode(&self) -> u8 {
         1
     }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        Some((Kind::Scanner, self.scan_id, true))
+    }
+}
+
+fn unmarshal_create_scanner(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::CreateScanner(CreateScanner {
+        scan_id: load_u32(data, 0),
+    }))
 }
 
 // Removes the scanner with the given scan_id. Once this is received by the server, it will no
 // longer send out EvtAdvertisementPackets with this scan_id.
+#[derive(Debug, PartialEq)]
 pub struct RemoveScanner {
     pub scan_id: u32,
 }
@@ -63,6 +206,17 @@ impl Command for RemoveScanner {
     fn opcode(&self) -> u8 {
         2
     }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        Some((Kind::Scanner, self.scan_id, false))
+    }
+}
+
+fn unmarshal_remove_scanner(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::RemoveScanner(RemoveScanner {
+        scan_id: load_u32(data, 0),
+    }))
 }
 
 // Creates a connection channel for a Flic button with the given bluetooth address. You assign a
@@ -70,6 +224,7 @@ impl Command for RemoveScanner {
 // refer to this connection channel. After this command is received by the server, an
 // EvtCreateConnectionChannelResponse is sent. If there already exists a connection channel with
 // this conn_id, this does nothing.
+#[derive(Debug, PartialEq)]
 pub struct CreateConnectionChannel {
     pub conn_id: u32,
     pub bd_addr: BdAddr,
@@ -79,7 +234,7 @@ pub struct CreateConnectionChannel {
 
 impl Command for CreateConnectionChannel {
     fn marshal(&self) -> Vec<u8> {
-        let lm = self.latency_mode as u8;
+        let lm = u8::from(self.latency_mode);
         let mut v = self.conn_id.to_le_bytes().to_vec();
         v.append(&mut self.bd_addr.to_vec());
         v.push(lm);
@@ -89,12 +244,29 @@ impl Command for CreateConnectionChannel {
     fn opcode(&self) -> u8 {
         3
     }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        Some((Kind::ConnectionChannel, self.conn_id, true))
+    }
+}
+
+fn unmarshal_create_connection_channel(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 13)?;
+
+    Ok(AnyCommand::CreateConnectionChannel(
+        CreateConnectionChannel {
+            conn_id: load_u32(data, 0),
+            bd_addr: load_bd_addr(data, 4),
+            latency_mode: LatencyMode::from(data[10]),
+            auto_disconnect_time: load_u16(data, 11),
+        },
+    ))
 }
 
 // Removes a connection channel previously created with CmdCreateConnectionChannel. After this is
 // received by the server, this connection channel is removed and no further events will be sent
 // for this channel. If there are no other connection channels active to this Flic button among any
 // client, the physical bluetooth connection is disconnected.
+#[derive(Debug, PartialEq)]
 pub struct RemoveConnectionChannel {
     pub conn_id: u32,
 }
@@ -106,10 +278,24 @@ impl Command for RemoveConnectionChannel {
     fn opcode(&self) -> u8 {
         4
     }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        Some((Kind::ConnectionChannel, self.conn_id, false))
+    }
+}
+
+fn unmarshal_remove_connection_channel(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::RemoveConnectionChannel(
+        RemoveConnectionChannel {
+            conn_id: load_u32(data, 0),
+        },
+    ))
 }
 
 // Removes all connection channels among all clients for the specified Flic button bluetooth
 // address.
+#[derive(Debug, PartialEq)]
 pub struct ForceDisconnect {
     pub bd_addr: BdAddr,
 }
@@ -123,9 +309,18 @@ impl Command for ForceDisconnect {
     }
 }
 
+fn unmarshal_force_disconnect(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 6)?;
+
+    Ok(AnyCommand::ForceDisconnect(ForceDisconnect {
+        bd_addr: load_bd_addr(data, 0),
+    }))
+}
+
 // Changes the accepted latency for this connection channel and the auto disconnect time. The
 // latency mode will be applied immediately but the auto disconnect time will be applied the next
 // time tme Flic is getting connected.
+#[derive(Debug, PartialEq)]
 pub struct ChangeModeParameters {
     pub conn_id: u32,
     pub latency_mode: LatencyMode,
@@ -134,7 +329,7 @@ pub struct ChangeModeParameters {
 
 impl Command for ChangeModeParameters {
     fn marshal(&self) -> Vec<u8> {
-        let lm = self.latency_mode as u8;
+        let lm = u8::from(self.latency_mode);
         let mut v = self.conn_id.to_le_bytes().to_vec();
         v.push(lm);
         v.append(&mut self.auto_disconnect_time.to_le_bytes().to_vec());
@@ -145,8 +340,19 @@ impl Command for ChangeModeParameters {
     }
 }
 
+fn unmarshal_change_mode_parameters(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 7)?;
+
+    Ok(AnyCommand::ChangeModeParameters(ChangeModeParameters {
+        conn_id: load_u32(data, 0),
+        latency_mode: LatencyMode::from(data[4]),
+        auto_disconnect_time: load_u16(data, 5),
+    }))
+}
+
 // If you for various reasons would like to ping the server, send this command. An EvtPingResponse
 // will be sent back in return with the same ping_id.
+#[derive(Debug, PartialEq)]
 pub struct Ping {
     pub ping_id: u32,
 }
@@ -160,8 +366,17 @@ impl Command for Ping {
     }
 }
 
+fn unmarshal_ping(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::Ping(Ping {
+        ping_id: load_u32(data, 0),
+    }))
+}
+
 // Get info about a verified button. An EvtGetButtonInfoResponse will be sent back immediately in
 // return with the bd_addr field set to the same value as in the request.
+#[derive(Debug, PartialEq)]
 pub struct GetButtonInfo {
     pub bd_addr: BdAddr,
 }
@@ -175,7 +390,16 @@ impl Command for GetButtonInfo {
     }
 }
 
+fn unmarshal_get_button_info(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 6)?;
+
+    Ok(AnyCommand::GetButtonInfo(GetButtonInfo {
+        bd_addr: load_bd_addr(data, 0),
+    }))
+}
+
 // Starts a scan wizard. If there already exists a scan wizard with the same id, this does nothing.
+#[derive(Debug, PartialEq)]
 pub struct CreateScanWizard {
     pub scan_wizard_id: u32,
 }
@@ -189,9 +413,18 @@ impl Command for CreateScanWizard {
     }
 }
 
+fn unmarshal_create_scan_wizard(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::CreateScanWizard(CreateScanWizard {
+        scan_wizard_id: load_u32(data, 0),
+    }))
+}
+
 // Cancels a scan wizard that was previously started. If there exists a scan wizard with this id,
 // it is cancelled and an EvtScanWizardCompleted is sent with the reason set to
 // WizardCancelledByUser.
+#[derive(Debug, PartialEq)]
 pub struct CancelScanWizard {
     pub scan_wizard_id: u32,
 }
@@ -205,11 +438,20 @@ impl Command for CancelScanWizard {
     }
 }
 
+fn unmarshal_cancel_scan_wizard(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::CancelScanWizard(CancelScanWizard {
+        scan_wizard_id: load_u32(data, 0),
+    }))
+}
+
 // Deletes a button. If the button exists in the list of verified buttons, all connection channels
 // will be removed for all clients for this button. After that the EvtButtonDeleted event will be
 // triggered for all clients. If the button does not exist in the list of verified buttons, the
 // request has no effects but an EvtButtonDeleted will be triggered anyway for this client with the
 // same address as in the request.
+#[derive(Debug, PartialEq)]
 pub struct DeleteButton {
     pub bd_addr: BdAddr,
 }
@@ -223,6 +465,14 @@ impl Command for DeleteButton {
     }
 }
 
+fn unmarshal_delete_button(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 6)?;
+
+    Ok(AnyCommand::DeleteButton(DeleteButton {
+        bd_addr: load_bd_addr(data, 0),
+    }))
+}
+
 // Creates a battery status listener for a specific button. If the given listener_id already exists
 // for this client, this does nothing. Once created, an EvtBatteryStatus will always immediately be
 // sent with the current battery status. Every time the battery status later updates, an
@@ -230,6 +480,7 @@ impl Command for DeleteButton {
 // Note that by just having a battery status listener doesn't mean flicd will automatically connect
 // to a Flic button in order to get updates. At least one client needs a connection channel for the
 // particular button to be able to get new updates.
+#[derive(Debug, PartialEq)]
 pub struct CreateBatteryStatusListener {
     pub listener_id: u32,
     pub bd_addr: BdAddr,
@@ -244,9 +495,24 @@ impl Command for CreateBatteryStatusListener {
     fn opcode(&self) -> u8 {
         12
     }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        Some((Kind::BatteryStatusListener, self.listener_id, true))
+    }
+}
+
+fn unmarshal_create_battery_status_listener(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 10)?;
+
+    Ok(AnyCommand::CreateBatteryStatusListener(
+        CreateBatteryStatusListener {
+            listener_id: load_u32(data, 0),
+            bd_addr: load_bd_addr(data, 4),
+        },
+    ))
 }
 
 // Removes a battery status listener.
+#[derive(Debug, PartialEq)]
 pub struct RemoveBatteryStatusListener {
     pub listener_id: u32,
 }
@@ -258,16 +524,101 @@ impl Command for RemoveBatteryStatusListener {
     fn opcode(&self) -> u8 {
         13
     }
+    fn tracked(&self) -> Option<(Kind, u32, bool)> {
+        Some((Kind::BatteryStatusListener, self.listener_id, false))
+    }
+}
+
+fn unmarshal_remove_battery_status_listener(data: &[u8]) -> Result<AnyCommand> {
+    check_sz(data, 4)?;
+
+    Ok(AnyCommand::RemoveBatteryStatusListener(
+        RemoveBatteryStatusListener {
+            listener_id: load_u32(data, 0),
+        },
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn assert_round_trips<C: Command>(msg: &C) {
+        let mut data = msg.marshal();
+        data.insert(0, msg.opcode());
+
+        let (got, opcode) = unmarshal(&data).expect("failed to unmarshal valid data");
+        assert_eq!(opcode as u8, msg.opcode());
+        assert_eq!(got.opcode(), msg.opcode());
+        assert_eq!(got.marshal(), msg.marshal());
+    }
+
+    #[test]
+    #[should_panic(expected = "BadOpcode")]
+    fn unrecognized_opcode_fails() {
+        let data = vec![0x0E, 0x78, 0x56, 0x34, 0x12];
+        unmarshal(&data).expect("failed to unmarshal data");
+    }
+
+    #[test]
+    #[should_panic(expected = "EmptyPacket")]
+    fn empty_command_fails() {
+        unmarshal(&[]).expect("failed to unmarshal data");
+    }
+
+    #[test]
+    #[should_panic(expected = "BadLength")]
+    fn truncated_command_fails() {
+        let data = vec![0x01, 0x78, 0x56, 0x34];
+        unmarshal(&data).expect("failed to unmarshal data");
+    }
+
+    #[test]
+    fn unknown_latency_mode() {
+        let data = vec![0x06, 0x78, 0x56, 0x34, 0x12, 0x03, 0x55, 0x44];
+        let (got, _) = unmarshal(&data).expect("failed to unmarshal data");
+        assert_eq!(
+            got,
+            AnyCommand::ChangeModeParameters(ChangeModeParameters {
+                conn_id: 0x12345678,
+                latency_mode: LatencyMode::Unknown(0x03),
+                auto_disconnect_time: 0x4455,
+            })
+        );
+        assert_round_trips(&ChangeModeParameters {
+            conn_id: 0x12345678,
+            latency_mode: LatencyMode::Unknown(0x03),
+            auto_disconnect_time: 0x4455,
+        });
+    }
+
+    #[test]
+    fn unmarshal_create_connection_channel() {
+        let data = vec![
+            0x03, // opcode
+            0x78, 0x56, 0x34, 0x12, // conn_id
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
+            0x02, // latency_mode
+            0x55, 0x44, // auto_disconnect_time
+        ];
+        let (got, opcode) = unmarshal(&data).expect("failed to unmarshal valid data");
+        assert_eq!(opcode, Opcode::CreateConnectionChannel);
+        assert_eq!(
+            got,
+            AnyCommand::CreateConnectionChannel(CreateConnectionChannel {
+                conn_id: 0x12345678,
+                bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                latency_mode: LatencyMode::High,
+                auto_disconnect_time: 0x4455,
+            })
+        );
+    }
+
     #[test]
     fn get_info_marshals_to_empty_vec() {
         let msg = GetInfo {};
-        assert_eq!(msg.marshal(), vec![]);
+        assert_eq!(msg.marshal(), Vec::<u8>::new());
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -276,6 +627,7 @@ mod tests {
             scan_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -284,6 +636,7 @@ mod tests {
             scan_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -303,6 +656,7 @@ mod tests {
                 0x55, 0x44, // auto_disconnect_time
             ]
         );
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -311,6 +665,7 @@ mod tests {
             conn_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -319,6 +674,7 @@ mod tests {
             bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
         };
         assert_eq!(msg.marshal(), vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -336,6 +692,7 @@ mod tests {
                 0x55, 0x44, // auto_disconnect_time
             ]
         );
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -344,6 +701,7 @@ mod tests {
             ping_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -352,6 +710,7 @@ mod tests {
             bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
         };
         assert_eq!(msg.marshal(), vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -360,6 +719,7 @@ mod tests {
             scan_wizard_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -368,6 +728,7 @@ mod tests {
             scan_wizard_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -376,6 +737,7 @@ mod tests {
             bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
         };
         assert_eq!(msg.marshal(), vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -391,6 +753,7 @@ mod tests {
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
             ]
         );
+        assert_round_trips(&msg);
     }
 
     #[test]
@@ -399,5 +762,6 @@ mod tests {
             listener_id: 0x12345678,
         };
         assert_eq!(msg.marshal(), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_round_trips(&msg);
     }
 }
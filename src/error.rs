@@ -9,12 +9,20 @@ use crate::enums;
 pub enum FlicError {
     Unmarshal(UnmarshalError),
     FlicD(io::Error),
+    ConnectionChannel(enums::CreateConnectionChannelError),
+    ScanWizard(enums::ScanWizardResult),
+    // A Manager's worker pool had no room for another handler call, so it was dropped. See
+    // Manager::on_error.
+    HandlerQueueFull,
+    // The connection to flicd ended or failed part way through a packet, so it can't be read any
+    // further. A client with a reconnect policy reconnects instead of returning this.
+    BrokenStream,
     Generic(String),
 }
 
 impl FlicError {
     pub fn from<T: error::Error>(desc: &str, err: T) -> FlicError {
-        FlicError::Generic(String::from(format!("{}: {}", desc, err)))
+        FlicError::Generic(format!("{}: {}", desc, err))
     }
 }
 
@@ -23,6 +31,14 @@ impl fmt::Display for FlicError {
         match *self {
             FlicError::Unmarshal(ref err) => write!(f, "failed during unmarshaling: {}", err),
             FlicError::FlicD(ref err) => write!(f, "failed communicating with flicd: {}", err),
+            FlicError::ConnectionChannel(ref err) => {
+                write!(f, "failed to create connection channel: {:?}", err)
+            }
+            FlicError::ScanWizard(ref result) => write!(f, "scan wizard failed: {:?}", result),
+            FlicError::HandlerQueueFull => write!(f, "handler queue is full"),
+            FlicError::BrokenStream => {
+                write!(f, "connection to flicd broke off in the middle of a packet")
+            }
             FlicError::Generic(ref err) => write!(f, "{}", err),
         }
     }
@@ -33,6 +49,10 @@ impl error::Error for FlicError {
         match *self {
             FlicError::Unmarshal(ref err) => Some(err),
             FlicError::FlicD(ref err) => Some(err),
+            FlicError::ConnectionChannel(_) => None,
+            FlicError::ScanWizard(_) => None,
+            FlicError::HandlerQueueFull => None,
+            FlicError::BrokenStream => None,
             FlicError::Generic(_) => None,
         }
     }
@@ -56,15 +76,18 @@ impl From<String> for FlicError {
     }
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum UnmarshalError {
     BadLength(usize, usize),
     BadLengthAtLeast(usize, usize),
     BadString(string::FromUtf8Error),
-    BadEnum(u8, String),
+    // The length given for a string, and the size of the field it's stored in.
+    BadStringLength(usize, usize),
     BadOpcode(u8),
     BadClickType(enums::ClickType, String),
     BadTimestamp(i64),
+    EmptyPacket,
 }
 
 impl fmt::Display for UnmarshalError {
@@ -79,14 +102,17 @@ impl fmt::Display for UnmarshalError {
                 got_len, want_at_least_len
             ),
             UnmarshalError::BadString(err) => write!(f, "string was not valid UTF-8: {}", err),
-            UnmarshalError::BadEnum(field, val) => {
-                write!(f, "enum value {} is invalid for enum {}", field, val)
-            }
+            UnmarshalError::BadStringLength(len, field_len) => write!(
+                f,
+                "string length {} doesn't fit in a field of {} bytes",
+                len, field_len
+            ),
             UnmarshalError::BadOpcode(opcode) => write!(f, "unknown opcode {:?}", opcode),
             UnmarshalError::BadClickType(click_type, btn_evt) => {
                 write!(f, "click type {:?} not valid for {}", click_type, btn_evt)
             }
             UnmarshalError::BadTimestamp(ts) => write!(f, "timestamp was invalid: {}", ts),
+            UnmarshalError::EmptyPacket => write!(f, "packet was empty, expected an opcode"),
         }
     }
 }
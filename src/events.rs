@@ -4,10 +4,12 @@
 use crate::enums::*;
 use crate::error::{FlicError, UnmarshalError};
 use crate::{BdAddr, Result, Uuid};
-use num::FromPrimitive;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(FromPrimitive, Debug, Clone, PartialEq, Eq, Hash)]
+// The opcode of each event flicd sends, followed by two that the client uses to tell events apart
+// but that never appear on the wire. Those two are numbered past u8::MAX so they can't collide with
+// an opcode flicd adds later, and have to be handled before casting an Opcode to a wire byte.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Opcode {
     AdvertisementPacket = 0,
     CreateConnectionChannelResponse = 1,
@@ -30,36 +32,85 @@ pub enum Opcode {
     ScanWizardCompleted = 18,
     ButtonDeleted = 19,
     BatteryStatus = 20,
+    // Stands in for any opcode we don't recognize, see Event::Unknown. Not a wire value.
+    Unknown = 0x100,
+    // Never sent by flicd, see Reconnected. Not a wire value.
+    Reconnected = 0x101,
 }
 
+type Unmarshaller = fn(&[u8]) -> Result<Event>;
+
 pub fn unmarshal(data: &[u8]) -> Result<(Event, Opcode)> {
-    let opcode = match FromPrimitive::from_u8(data[0]) {
-        Some(opcode) => opcode,
-        None => return Err(FlicError::Unmarshal(UnmarshalError::BadOpcode(data[0]))),
-    };
+    if data.is_empty() {
+        return Err(FlicError::Unmarshal(UnmarshalError::EmptyPacket));
+    }
 
-    let unmarshal_event = match opcode {
-        Opcode::AdvertisementPacket => unmarshal_advertisement_packet,
-        Opcode::CreateConnectionChannelResponse => unmarshal_create_connection_channel_response,
-        Opcode::ConnectionStatusChanged => unmarshal_connection_status_changed,
-        Opcode::ConnectionChannelRemoved => unmarshal_connection_channel_removed,
-        Opcode::ButtonUpOrDown => unmarshal_button_up_or_down,
-        Opcode::ButtonClickOrHold => unmarshal_button_click_or_hold,
-        Opcode::ButtonSingleOrDoubleClick => unmarshal_button_single_or_double_click,
-        Opcode::ButtonSingleOrDoubleClickOrHold => unmarshal_button_single_or_double_click_or_hold,
-        Opcode::NewVerifiedButton => unmarshal_new_verified_button,
-        Opcode::GetInfoResponse => unmarshal_get_info_response,
-        Opcode::NoSpaceForNewConnection => unmarshal_no_space_for_new_connection,
-        Opcode::GotSpaceForNewConnection => unmarshal_got_space_for_new_connection,
-        Opcode::BluetoothControllerStateChange => unmarshal_bluetooth_controller_state_change,
-        Opcode::PingResponse => unmarshal_ping_response,
-        Opcode::GetButtonInfoResponse => unmarshal_get_button_info_response,
-        Opcode::ScanWizardFoundPrivateButton => unmarshal_scan_wizard_found_private_button,
-        Opcode::ScanWizardFoundPublicButton => unmarshal_scan_wizard_found_public_button,
-        Opcode::ScanWizardButtonConnected => unmarshal_scan_wizard_button_connected,
-        Opcode::ScanWizardCompleted => unmarshal_scan_wizard_completed,
-        Opcode::ButtonDeleted => unmarshal_button_deleted,
-        Opcode::BatteryStatus => unmarshal_battery_status,
+    let (opcode, unmarshal_event): (Opcode, Unmarshaller) = match data[0] {
+        0 => (Opcode::AdvertisementPacket, unmarshal_advertisement_packet),
+        1 => (
+            Opcode::CreateConnectionChannelResponse,
+            unmarshal_create_connection_channel_response,
+        ),
+        2 => (
+            Opcode::ConnectionStatusChanged,
+            unmarshal_connection_status_changed,
+        ),
+        3 => (
+            Opcode::ConnectionChannelRemoved,
+            unmarshal_connection_channel_removed,
+        ),
+        4 => (Opcode::ButtonUpOrDown, unmarshal_button_up_or_down),
+        5 => (Opcode::ButtonClickOrHold, unmarshal_button_click_or_hold),
+        6 => (
+            Opcode::ButtonSingleOrDoubleClick,
+            unmarshal_button_single_or_double_click,
+        ),
+        7 => (
+            Opcode::ButtonSingleOrDoubleClickOrHold,
+            unmarshal_button_single_or_double_click_or_hold,
+        ),
+        8 => (Opcode::NewVerifiedButton, unmarshal_new_verified_button),
+        9 => (Opcode::GetInfoResponse, unmarshal_get_info_response),
+        10 => (
+            Opcode::NoSpaceForNewConnection,
+            unmarshal_no_space_for_new_connection,
+        ),
+        11 => (
+            Opcode::GotSpaceForNewConnection,
+            unmarshal_got_space_for_new_connection,
+        ),
+        12 => (
+            Opcode::BluetoothControllerStateChange,
+            unmarshal_bluetooth_controller_state_change,
+        ),
+        13 => (Opcode::PingResponse, unmarshal_ping_response),
+        14 => (
+            Opcode::GetButtonInfoResponse,
+            unmarshal_get_button_info_response,
+        ),
+        15 => (
+            Opcode::ScanWizardFoundPrivateButton,
+            unmarshal_scan_wizard_found_private_button,
+        ),
+        16 => (
+            Opcode::ScanWizardFoundPublicButton,
+            unmarshal_scan_wizard_found_public_button,
+        ),
+        17 => (
+            Opcode::ScanWizardButtonConnected,
+            unmarshal_scan_wizard_button_connected,
+        ),
+        18 => (Opcode::ScanWizardCompleted, unmarshal_scan_wizard_completed),
+        19 => (Opcode::ButtonDeleted, unmarshal_button_deleted),
+        20 => (Opcode::BatteryStatus, unmarshal_battery_status),
+        // Most likely an event added in a newer version of flicd.
+        opcode => {
+            let evt = Event::Unknown {
+                opcode,
+                body: data[1..].to_vec(),
+            };
+            return Ok((evt, Opcode::Unknown));
+        }
     };
 
     let evt = unmarshal_event(&data[1..])?;
@@ -67,7 +118,25 @@ pub fn unmarshal(data: &[u8]) -> Result<(Event, Opcode)> {
     Ok((evt, opcode))
 }
 
+/// An event sent by flicd.
+///
+/// With the `serde` feature, an event is (de)serialized as an object holding its fields plus a
+/// `"type"` naming the variant in snake_case:
+///
+/// ```json
+/// {"type":"button_up_or_down","conn_id":1,"click_type":"button_down","was_queued":false,"time_diff":0}
+/// ```
+///
+/// - `BdAddr` and `Uuid` fields are strings in the form they're displayed in.
+/// - `BatteryStatus::timestamp` is an RFC 3339 timestamp in UTC, e.g. `"2020-04-23T15:05:10Z"`.
+/// - Enum values are their snake_case names. Values this client doesn't know about are written as
+///   `{"unknown":7}`.
 #[derive(Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
 pub enum Event {
     AdvertisementPacket(AdvertisementPacket),
     CreateConnectionChannelResponse(CreateConnectionChannelResponse),
@@ -90,9 +159,190 @@ pub enum Event {
     ScanWizardCompleted(ScanWizardCompleted),
     ButtonDeleted(ButtonDeleted),
     BatteryStatus(BatteryStatus),
+    Reconnected(Reconnected),
+    // An event this version of the client doesn't know how to read, e.g. one added in a newer
+    // version of flicd. Its opcode and body are kept as they arrived.
+    Unknown { opcode: u8, body: Vec<u8> },
 }
 
-fn check_sz_at_least(data: &[u8], want_len: usize) -> Result<()> {
+impl Event {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Event::AdvertisementPacket(_) => Opcode::AdvertisementPacket,
+            Event::CreateConnectionChannelResponse(_) => Opcode::CreateConnectionChannelResponse,
+            Event::ConnectionStatusChanged(_) => Opcode::ConnectionStatusChanged,
+            Event::ConnectionChannelRemoved(_) => Opcode::ConnectionChannelRemoved,
+            Event::ButtonUpOrDown(_) => Opcode::ButtonUpOrDown,
+            Event::ButtonClickOrHold(_) => Opcode::ButtonClickOrHold,
+            Event::ButtonSingleOrDoubleClick(_) => Opcode::ButtonSingleOrDoubleClick,
+            Event::ButtonSingleOrDoubleClickOrHold(_) => Opcode::ButtonSingleOrDoubleClickOrHold,
+            Event::NewVerifiedButton(_) => Opcode::NewVerifiedButton,
+            Event::GetInfoResponse(_) => Opcode::GetInfoResponse,
+            Event::NoSpaceForNewConnection(_) => Opcode::NoSpaceForNewConnection,
+            Event::GotSpaceForNewConnection(_) => Opcode::GotSpaceForNewConnection,
+            Event::BluetoothControllerStateChange(_) => Opcode::BluetoothControllerStateChange,
+            Event::PingResponse(_) => Opcode::PingResponse,
+            Event::GetButtonInfoResponse(_) => Opcode::GetButtonInfoResponse,
+            Event::ScanWizardFoundPrivateButton(_) => Opcode::ScanWizardFoundPrivateButton,
+            Event::ScanWizardFoundPublicButton(_) => Opcode::ScanWizardFoundPublicButton,
+            Event::ScanWizardButtonConnected(_) => Opcode::ScanWizardButtonConnected,
+            Event::ScanWizardCompleted(_) => Opcode::ScanWizardCompleted,
+            Event::ButtonDeleted(_) => Opcode::ButtonDeleted,
+            Event::BatteryStatus(_) => Opcode::BatteryStatus,
+            Event::Reconnected(_) => Opcode::Reconnected,
+            Event::Unknown { .. } => Opcode::Unknown,
+        }
+    }
+
+    // The connection channel the event is about, if it's about one.
+    pub fn conn_id(&self) -> Option<u32> {
+        match self {
+            Event::CreateConnectionChannelResponse(evt) => Some(evt.conn_id),
+            Event::ConnectionStatusChanged(evt) => Some(evt.conn_id),
+            Event::ConnectionChannelRemoved(evt) => Some(evt.conn_id),
+            Event::ButtonUpOrDown(evt) => Some(evt.conn_id),
+            Event::ButtonClickOrHold(evt) => Some(evt.conn_id),
+            Event::ButtonSingleOrDoubleClick(evt) => Some(evt.conn_id),
+            Event::ButtonSingleOrDoubleClickOrHold(evt) => Some(evt.conn_id),
+            _ => None,
+        }
+    }
+
+    // The button the event names directly, if any. Events about a connection channel don't
+    // include the button's address, see conn_id.
+    pub fn bd_addr(&self) -> Option<&BdAddr> {
+        match self {
+            Event::AdvertisementPacket(evt) => Some(&evt.bd_addr),
+            Event::NewVerifiedButton(evt) => Some(&evt.bd_addr),
+            Event::GetButtonInfoResponse(evt) => Some(&evt.bd_addr),
+            Event::ScanWizardFoundPublicButton(evt) => Some(&evt.bd_addr),
+            Event::ButtonDeleted(evt) => Some(&evt.bd_addr),
+            _ => None,
+        }
+    }
+
+    // Returns the body of the event as it would be sent by flicd, not including the opcode or the
+    // length header. This is the inverse of the unmarshal_* functions.
+    pub fn marshal(&self) -> Vec<u8> {
+        match self {
+            Event::AdvertisementPacket(evt) => marshal_advertisement_packet(evt),
+            Event::CreateConnectionChannelResponse(evt) => {
+                marshal_create_connection_channel_response(evt)
+            }
+            Event::ConnectionStatusChanged(evt) => marshal_connection_status_changed(evt),
+            Event::ConnectionChannelRemoved(evt) => marshal_connection_channel_removed(evt),
+            Event::ButtonUpOrDown(evt) => marshal_base_button_event(
+                evt.conn_id,
+                evt.click_type,
+                evt.was_queued,
+                evt.time_diff,
+            ),
+            Event::ButtonClickOrHold(evt) => marshal_base_button_event(
+                evt.conn_id,
+                evt.click_type,
+                evt.was_queued,
+                evt.time_diff,
+            ),
+            Event::ButtonSingleOrDoubleClick(evt) => marshal_base_button_event(
+                evt.conn_id,
+                evt.click_type,
+                evt.was_queued,
+                evt.time_diff,
+            ),
+            Event::ButtonSingleOrDoubleClickOrHold(evt) => marshal_base_button_event(
+                evt.conn_id,
+                evt.click_type,
+                evt.was_queued,
+                evt.time_diff,
+            ),
+            Event::NewVerifiedButton(evt) => evt.bd_addr.to_vec(),
+            Event::GetInfoResponse(evt) => marshal_get_info_response(evt),
+            Event::NoSpaceForNewConnection(evt) => vec![evt.max_concurrently_connected_buttons],
+            Event::GotSpaceForNewConnection(evt) => vec![evt.max_concurrently_connected_buttons],
+            Event::BluetoothControllerStateChange(evt) => vec![u8::from(evt.state)],
+            Event::PingResponse(evt) => evt.ping_id.to_le_bytes().to_vec(),
+            Event::GetButtonInfoResponse(evt) => marshal_get_button_info_response(evt),
+            Event::ScanWizardFoundPrivateButton(evt) => evt.scan_wizard_id.to_le_bytes().to_vec(),
+            Event::ScanWizardFoundPublicButton(evt) => marshal_scan_wizard_found_public_button(evt),
+            Event::ScanWizardButtonConnected(evt) => evt.scan_wizard_id.to_le_bytes().to_vec(),
+            Event::ScanWizardCompleted(evt) => marshal_scan_wizard_completed(evt),
+            Event::ButtonDeleted(evt) => marshal_button_deleted(evt),
+            Event::BatteryStatus(evt) => marshal_battery_status(evt),
+            // Reconnected never goes over the wire, so it has no body.
+            Event::Reconnected(_) => Vec::new(),
+            Event::Unknown { body, .. } => body.clone(),
+        }
+    }
+}
+
+// Marshals the event into a full packet as it appears on the wire, minus the length header: the
+// opcode followed by the body. This is the inverse of unmarshal. Reconnected is made up by the
+// client and has no wire form, so it marshals to an empty packet.
+pub fn marshal(evt: &Event) -> Vec<u8> {
+    let opcode = match evt {
+        Event::Unknown { opcode, .. } => *opcode,
+        Event::Reconnected(_) => return Vec::new(),
+        _ => evt.opcode() as u8,
+    };
+
+    let mut data = evt.marshal();
+    data.insert(0, opcode);
+    data
+}
+
+/// Implemented by each event struct, tying it to its `Event` variant and `Opcode`. This is what
+/// lets `Manager::on` hand handlers the concrete event.
+pub trait EventType: Sized {
+    const OPCODE: Opcode;
+
+    /// Returns the struct inside `evt`, if it's this type of event.
+    fn from_event(evt: &Event) -> Option<&Self>;
+}
+
+// Each event struct has the same name as its Event variant and Opcode.
+macro_rules! event_types {
+    ($($name:ident),* $(,)?) => {
+        $(
+            impl EventType for $name {
+                const OPCODE: Opcode = Opcode::$name;
+
+                fn from_event(evt: &Event) -> Option<&$name> {
+                    match evt {
+                        Event::$name(evt) => Some(evt),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+    };
+}
+
+event_types!(
+    AdvertisementPacket,
+    CreateConnectionChannelResponse,
+    ConnectionStatusChanged,
+    ConnectionChannelRemoved,
+    ButtonUpOrDown,
+    ButtonClickOrHold,
+    ButtonSingleOrDoubleClick,
+    ButtonSingleOrDoubleClickOrHold,
+    NewVerifiedButton,
+    GetInfoResponse,
+    NoSpaceForNewConnection,
+    GotSpaceForNewConnection,
+    BluetoothControllerStateChange,
+    PingResponse,
+    GetButtonInfoResponse,
+    ScanWizardFoundPrivateButton,
+    ScanWizardFoundPublicButton,
+    ScanWizardButtonConnected,
+    ScanWizardCompleted,
+    ButtonDeleted,
+    BatteryStatus,
+    Reconnected,
+);
+
+pub(crate) fn check_sz_at_least(data: &[u8], want_len: usize) -> Result<()> {
     if data.len() >= want_len {
         return Ok(());
     }
@@ -103,7 +353,7 @@ fn check_sz_at_least(data: &[u8], want_len: usize) -> Result<()> {
     )))
 }
 
-fn check_sz(data: &[u8], want_len: usize) -> Result<()> {
+pub(crate) fn check_sz(data: &[u8], want_len: usize) -> Result<()> {
     if data.len() == want_len {
         return Ok(());
     }
@@ -118,11 +368,11 @@ fn load_bool(data: &[u8], o: usize) -> bool {
     data[o] == 1
 }
 
-fn load_u16(data: &[u8], o: usize) -> u16 {
+pub(crate) fn load_u16(data: &[u8], o: usize) -> u16 {
     u16::from_le_bytes([data[o], data[o + 1]])
 }
 
-fn load_u32(data: &[u8], o: usize) -> u32 {
+pub(crate) fn load_u32(data: &[u8], o: usize) -> u32 {
     u32::from_le_bytes([data[o], data[o + 1], data[o + 2], data[o + 3]])
 }
 
@@ -143,15 +393,26 @@ fn load_timestamp(data: &[u8], o: usize) -> Result<SystemTime> {
     ]);
 
     if secs_since_epoch > 0 {
-        return Ok(UNIX_EPOCH + Duration::from_secs(secs_since_epoch as u64));
+        // Far enough in the future, this doesn't fit in a SystemTime on every platform.
+        if let Some(ts) = UNIX_EPOCH.checked_add(Duration::from_secs(secs_since_epoch as u64)) {
+            return Ok(ts);
+        }
     }
 
-    return Err(FlicError::Unmarshal(UnmarshalError::BadTimestamp(
+    Err(FlicError::Unmarshal(UnmarshalError::BadTimestamp(
         secs_since_epoch,
-    )));
+    )))
 }
 
-fn load_string(data: &[u8], o: usize, sz: usize) -> Result<String> {
+// Loads the first sz bytes of a string field that takes up field_sz bytes on the wire. sz comes off
+// the wire too, so it's checked against the size of the field before it's used.
+fn load_string(data: &[u8], o: usize, sz: usize, field_sz: usize) -> Result<String> {
+    if sz > field_sz {
+        return Err(FlicError::Unmarshal(UnmarshalError::BadStringLength(
+            sz, field_sz,
+        )));
+    }
+
     let res = String::from_utf8(data[o..(o + sz)].to_vec());
     match res {
         Ok(s) => Ok(s),
@@ -159,7 +420,7 @@ fn load_string(data: &[u8], o: usize, sz: usize) -> Result<String> {
     }
 }
 
-fn load_bd_addr(data: &[u8], o: usize) -> BdAddr {
+pub(crate) fn load_bd_addr(data: &[u8], o: usize) -> BdAddr {
     BdAddr([
         data[o],
         data[o + 1],
@@ -191,11 +452,32 @@ fn load_uuid(data: &[u8], o: usize) -> Uuid {
     ])
 }
 
+fn store_string(v: &mut Vec<u8>, s: &str, sz: usize) {
+    // The string is truncated to fit, but never in the middle of a character, so it still decodes.
+    let mut len = s.len().min(sz);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    v.push(len as u8);
+    v.extend_from_slice(&s.as_bytes()[..len]);
+    v.resize(v.len() + sz - len, 0);
+}
+
+fn store_timestamp(v: &mut Vec<u8>, ts: SystemTime) {
+    let secs_since_epoch = match ts.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    };
+    v.extend_from_slice(&secs_since_epoch.to_le_bytes());
+}
+
 // For each scanner the client has created, this packet will be sent for each bluetooth
 // advertisement packet arriving that comes from a Flic button. Usually the Flic button sends out
 // many advertisement packets, with higher frequency if it was lately pressed.
 // Opcode: 0
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AdvertisementPacket {
     pub scan_id: u32, // The scan id corresponding to the scanner which this advertisement packet belongs to.
     pub bd_addr: BdAddr, // The bluetooth address of this Flic button. Use it to establish a connection chnanel.
@@ -220,7 +502,7 @@ fn unmarshal_advertisement_packet(data: &[u8]) -> Result<Event> {
         scan_id: load_u32(data, 0),
         bd_addr: load_bd_addr(data, 4),
         // data[10] is the length of the name field.
-        name: load_string(data, 11, name_len)?,
+        name: load_string(data, 11, name_len, 16)?,
         rssi: data[27] as i8,
         is_private: load_bool(data, 28),
         already_verified: load_bool(data, 29),
@@ -231,10 +513,23 @@ fn unmarshal_advertisement_packet(data: &[u8]) -> Result<Event> {
     Ok(Event::AdvertisementPacket(evt))
 }
 
+fn marshal_advertisement_packet(evt: &AdvertisementPacket) -> Vec<u8> {
+    let mut v = evt.scan_id.to_le_bytes().to_vec();
+    v.append(&mut evt.bd_addr.to_vec());
+    store_string(&mut v, &evt.name, 16);
+    v.push(evt.rssi as u8);
+    v.push(evt.is_private as u8);
+    v.push(evt.already_verified as u8);
+    v.push(evt.already_connected_to_this_device as u8);
+    v.push(evt.already_connected_to_other_device as u8);
+    v
+}
+
 // This event will always be sent when a CmdCreateConnectionChannel is received, containing the
 // status of the request.
 // Opcode: 1
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CreateConnectionChannelResponse {
     pub conn_id: u32,                        // Connection channel identifier.
     pub error: CreateConnectionChannelError, // Whether the request succeeded or not.
@@ -244,25 +539,9 @@ pub struct CreateConnectionChannelResponse {
 fn unmarshal_create_connection_channel_response(data: &[u8]) -> Result<Event> {
     check_sz(data, 6)?;
 
-    let error = match FromPrimitive::from_u8(data[4]) {
-        Some(err) => err,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[4],
-                String::from("CreateConnectionChannelError"),
-            )))
-        }
-    };
+    let error = CreateConnectionChannelError::from(data[4]);
 
-    let connection_status = match FromPrimitive::from_u8(data[5]) {
-        Some(conn_status) => conn_status,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[5],
-                String::from("ConnectionStatus"),
-            )))
-        }
-    };
+    let connection_status = ConnectionStatus::from(data[5]);
 
     let evt = CreateConnectionChannelResponse {
         conn_id: load_u32(data, 0),
@@ -273,9 +552,17 @@ fn unmarshal_create_connection_channel_response(data: &[u8]) -> Result<Event> {
     Ok(Event::CreateConnectionChannelResponse(evt))
 }
 
+fn marshal_create_connection_channel_response(evt: &CreateConnectionChannelResponse) -> Vec<u8> {
+    let mut v = evt.conn_id.to_le_bytes().to_vec();
+    v.push(u8::from(evt.error));
+    v.push(u8::from(evt.connection_status));
+    v
+}
+
 // This event is sent when the connection status is changed.
 // Opcode: 2
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionStatusChanged {
     pub conn_id: u32,                        // Connection channel identifier.
     pub connection_status: ConnectionStatus, // New connection status.
@@ -285,25 +572,9 @@ pub struct ConnectionStatusChanged {
 fn unmarshal_connection_status_changed(data: &[u8]) -> Result<Event> {
     check_sz(data, 6)?;
 
-    let connection_status = match FromPrimitive::from_u8(data[4]) {
-        Some(conn_status) => conn_status,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[4],
-                String::from("ConnectionStatus"),
-            )))
-        }
-    };
+    let connection_status = ConnectionStatus::from(data[4]);
 
-    let disconnect_reason = match FromPrimitive::from_u8(data[5]) {
-        Some(disconn_reason) => disconn_reason,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[5],
-                String::from("DisconnectionReason"),
-            )))
-        }
-    };
+    let disconnect_reason = DisconnectReason::from(data[5]);
 
     let evt = ConnectionStatusChanged {
         conn_id: load_u32(data, 0),
@@ -314,6 +585,13 @@ fn unmarshal_connection_status_changed(data: &[u8]) -> Result<Event> {
     Ok(Event::ConnectionStatusChanged(evt))
 }
 
+fn marshal_connection_status_changed(evt: &ConnectionStatusChanged) -> Vec<u8> {
+    let mut v = evt.conn_id.to_le_bytes().to_vec();
+    v.push(u8::from(evt.connection_status));
+    v.push(u8::from(evt.disconnect_reason));
+    v
+}
+
 // This event is sent when a connection channel is removed. After this event is sent from the
 // server, it will no longer send events corresponding to this connection channel. From this point,
 // the conn_id can now be reused when creating new connection channels. Note: If you got an
@@ -321,6 +599,7 @@ fn unmarshal_connection_status_changed(data: &[u8]) -> Result<Event> {
 // have never been considered created, and this event will thus never be sent afterwards.
 // Opcode: 3
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ConnectionChannelRemoved {
     pub conn_id: u32,                  // Connection channel identifier.
     pub removed_reason: RemovedReason, // Reason for this connection channel being removed.
@@ -329,15 +608,7 @@ pub struct ConnectionChannelRemoved {
 fn unmarshal_connection_channel_removed(data: &[u8]) -> Result<Event> {
     check_sz(data, 5)?;
 
-    let removed_reason = match FromPrimitive::from_u8(data[4]) {
-        Some(rem_reason) => rem_reason,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[4],
-                String::from("RemoveReason"),
-            )))
-        }
-    };
+    let removed_reason = RemovedReason::from(data[4]);
 
     let evt = ConnectionChannelRemoved {
         conn_id: load_u32(data, 0),
@@ -347,6 +618,12 @@ fn unmarshal_connection_channel_removed(data: &[u8]) -> Result<Event> {
     Ok(Event::ConnectionChannelRemoved(evt))
 }
 
+fn marshal_connection_channel_removed(evt: &ConnectionChannelRemoved) -> Vec<u8> {
+    let mut v = evt.conn_id.to_le_bytes().to_vec();
+    v.push(u8::from(evt.removed_reason));
+    v
+}
+
 // There are four types of button events. For each type of event, there is a different set of
 // possible ClickTypes. Normally one application would handle one type of events and discard the
 // others, depending on how many different triggers you would like the Flic button to be used for.
@@ -361,15 +638,7 @@ struct BaseButtonEvent {
 fn unmarshal_base_button_event(data: &[u8]) -> Result<BaseButtonEvent> {
     check_sz(data, 10)?;
 
-    let click_type = match FromPrimitive::from_u8(data[4]) {
-        Some(click_type) => click_type,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[4],
-                String::from("ClickType"),
-            )))
-        }
-    };
+    let click_type = ClickType::from(data[4]);
 
     Ok(BaseButtonEvent {
         conn_id: load_u32(data, 0),
@@ -379,10 +648,24 @@ fn unmarshal_base_button_event(data: &[u8]) -> Result<BaseButtonEvent> {
     })
 }
 
+fn marshal_base_button_event(
+    conn_id: u32,
+    click_type: ClickType,
+    was_queued: bool,
+    time_diff: u32,
+) -> Vec<u8> {
+    let mut v = conn_id.to_le_bytes().to_vec();
+    v.push(u8::from(click_type));
+    v.push(was_queued as u8);
+    v.append(&mut time_diff.to_le_bytes().to_vec());
+    v
+}
+
 // Possible ClickTypes are ButtonUp and ButtonDown. Used to simply know when the button was pressed
 // or released.
 // Opcode: 4
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ButtonUpOrDown {
     pub conn_id: u32,          // Connection channel identifier.
     pub click_type: ClickType, // The click type. For each opcode, there are different possible values.
@@ -394,7 +677,7 @@ fn unmarshal_button_up_or_down(data: &[u8]) -> Result<Event> {
     let base_event = unmarshal_base_button_event(data)?;
 
     match base_event.click_type {
-        ClickType::ButtonUp | ClickType::ButtonDown => (), // This is fine
+        ClickType::ButtonUp | ClickType::ButtonDown | ClickType::Unknown(_) => (), // This is fine
         _ => {
             return Err(FlicError::Unmarshal(UnmarshalError::BadClickType(
                 base_event.click_type,
@@ -415,6 +698,7 @@ fn unmarshal_button_up_or_down(data: &[u8]) -> Result<Event> {
 // click and hold.
 // Opcode: 5
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ButtonClickOrHold {
     pub conn_id: u32,          // Connection channel identifier.
     pub click_type: ClickType, // The click type. For each opcode, there are different possible values.
@@ -426,7 +710,7 @@ fn unmarshal_button_click_or_hold(data: &[u8]) -> Result<Event> {
     let base_event = unmarshal_base_button_event(data)?;
 
     match base_event.click_type {
-        ClickType::ButtonClick | ClickType::ButtonHold => (), // This is fine
+        ClickType::ButtonClick | ClickType::ButtonHold | ClickType::Unknown(_) => (), // This is fine
         _ => {
             return Err(FlicError::Unmarshal(UnmarshalError::BadClickType(
                 base_event.click_type,
@@ -447,6 +731,7 @@ fn unmarshal_button_click_or_hold(data: &[u8]) -> Result<Event> {
 // between a single click and a double click.
 // Opcode: 6
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ButtonSingleOrDoubleClick {
     pub conn_id: u32,          // Connection channel identifier.
     pub click_type: ClickType, // The click type. For each opcode, there are different possible values.
@@ -458,7 +743,7 @@ fn unmarshal_button_single_or_double_click(data: &[u8]) -> Result<Event> {
     let base_event = unmarshal_base_button_event(data)?;
 
     match base_event.click_type {
-        ClickType::ButtonSingleClick | ClickType::ButtonDoubleClick => (), // This is fine
+        ClickType::ButtonSingleClick | ClickType::ButtonDoubleClick | ClickType::Unknown(_) => (), // This is fine
         _ => {
             return Err(FlicError::Unmarshal(UnmarshalError::BadClickType(
                 base_event.click_type,
@@ -481,6 +766,7 @@ fn unmarshal_button_single_or_double_click(data: &[u8]) -> Result<Event> {
 // distinguish between a single click, a double click and a hold.
 // Opcode: 7
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ButtonSingleOrDoubleClickOrHold {
     pub conn_id: u32,          // Connection channel identifier.
     pub click_type: ClickType, // The click type. For each opcode, there are different possible values.
@@ -492,7 +778,10 @@ fn unmarshal_button_single_or_double_click_or_hold(data: &[u8]) -> Result<Event>
     let base_event = unmarshal_base_button_event(data)?;
 
     match base_event.click_type {
-        ClickType::ButtonSingleClick | ClickType::ButtonDoubleClick | ClickType::ButtonHold => (), // This is fine
+        ClickType::ButtonSingleClick
+        | ClickType::ButtonDoubleClick
+        | ClickType::ButtonHold
+        | ClickType::Unknown(_) => (), // This is fine
         _ => {
             return Err(FlicError::Unmarshal(UnmarshalError::BadClickType(
                 base_event.click_type,
@@ -516,6 +805,7 @@ fn unmarshal_button_single_or_double_click_or_hold(data: &[u8]) -> Result<Event>
 // EvtConnectionStatusChanged with connection_status = Ready will be sent just before this event.
 // Opcode: 8
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NewVerifiedButton {
     pub bd_addr: BdAddr, // The bluetooth address for the verified Flic button.
 }
@@ -533,6 +823,7 @@ fn unmarshal_new_verified_button(data: &[u8]) -> Result<Event> {
 // This is sent as a response to a CmdGetInfo.
 // Opcode: 9
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GetInfoResponse {
     pub bluetooth_controller_state: BluetoothControllerState, // Current state of the HCI connection to the bluetooth controller.
     pub my_bd_addr: BdAddr, // Current bluetooth address / identity of this device.
@@ -555,25 +846,9 @@ fn unmarshal_get_info_response(data: &[u8]) -> Result<Event> {
     // Now we can see if the total size makes sense.
     check_sz(data, 15 + nb_verified_buttons * 6)?;
 
-    let bluetooth_controller_state = match FromPrimitive::from_u8(data[0]) {
-        Some(status) => status,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[0],
-                String::from("BluetoothControllerState"),
-            )))
-        }
-    };
+    let bluetooth_controller_state = BluetoothControllerState::from(data[0]);
 
-    let my_bd_addr_type = match FromPrimitive::from_u8(data[7]) {
-        Some(addr_type) => addr_type,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[7],
-                String::from("BdAddrType"),
-            )))
-        }
-    };
+    let my_bd_addr_type = BdAddrType::from(data[7]);
 
     let mut bd_addr_of_verified_buttons = Vec::with_capacity(nb_verified_buttons);
 
@@ -596,12 +871,37 @@ fn unmarshal_get_info_response(data: &[u8]) -> Result<Event> {
     Ok(Event::GetInfoResponse(evt))
 }
 
+fn marshal_get_info_response(evt: &GetInfoResponse) -> Vec<u8> {
+    let mut v = vec![u8::from(evt.bluetooth_controller_state)];
+    v.append(&mut evt.my_bd_addr.to_vec());
+    v.push(u8::from(evt.my_bd_addr_type));
+    v.push(evt.max_pending_connections);
+    v.append(
+        &mut evt
+            .max_concurrently_connected_buttons
+            .to_le_bytes()
+            .to_vec(),
+    );
+    v.push(evt.current_pending_connections);
+    v.push(evt.currently_no_space_for_new_connection as u8);
+
+    // The length of the list is what goes on the wire, so that the result always unmarshals
+    // cleanly, even if nb_verified_buttons disagrees with it.
+    let nb_verified_buttons = evt.bd_addr_of_verified_buttons.len() as u16;
+    v.append(&mut nb_verified_buttons.to_le_bytes().to_vec());
+    for bd_addr in &evt.bd_addr_of_verified_buttons {
+        v.append(&mut bd_addr.to_vec());
+    }
+    v
+}
+
 // Sent when the maximum number of connections has been reached (immediately after the
 // EvtConnectionStatusChanged event). If the maximum number of connections is unknown, it is sent
 // when the maximum number of connections are reached and an attempt is made to connect yet another
 // button.
 // Opcode: 10
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NoSpaceForNewConnection {
     pub max_concurrently_connected_buttons: u8, // Same as in EvtGetInfoResponse.
 }
@@ -622,6 +922,7 @@ fn unmarshal_no_space_for_new_connection(data: &[u8]) -> Result<Event> {
 // connection.
 // Opcode: 11
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GotSpaceForNewConnection {
     pub max_concurrently_connected_buttons: u8, // Same as in EvtGetInfoResponse.
 }
@@ -647,6 +948,7 @@ fn unmarshal_got_space_for_new_connection(data: &[u8]) -> Result<Event> {
 // reset, back to Attached.
 // Opcode: 12
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BluetoothControllerStateChange {
     pub state: BluetoothControllerState, // The new state.
 }
@@ -654,15 +956,7 @@ pub struct BluetoothControllerStateChange {
 fn unmarshal_bluetooth_controller_state_change(data: &[u8]) -> Result<Event> {
     check_sz(data, 1)?;
 
-    let state = match FromPrimitive::from_u8(data[0]) {
-        Some(state) => state,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[0],
-                String::from("BluetoothControllerState"),
-            )))
-        }
-    };
+    let state = BluetoothControllerState::from(data[0]);
 
     let evt = BluetoothControllerStateChange { state };
 
@@ -672,6 +966,7 @@ fn unmarshal_bluetooth_controller_state_change(data: &[u8]) -> Result<Event> {
 // Sent in response to a CmdPing
 // Opcode: 13
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PingResponse {
     pub ping_id: u32, // Same ping id as sent in the CmdPing.
 }
@@ -690,6 +985,7 @@ fn unmarshal_ping_response(data: &[u8]) -> Result<Event> {
 // bd_addr will contain zero-bytes.
 // Opcode: 14
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GetButtonInfoResponse {
     pub bd_addr: BdAddr, // The bluetooth device address of the request.
     pub uuid: Uuid,      // The uuid of the button. Each button has a unique 128-bit identifier.
@@ -715,17 +1011,26 @@ fn unmarshal_get_button_info_response(data: &[u8]) -> Result<Event> {
     let evt = GetButtonInfoResponse {
         bd_addr: load_bd_addr(data, 0),
         uuid: load_uuid(data, 6),
-        color: load_string(data, 23, color_len)?,
-        serial_number: load_string(data, 40, serial_number_len)?,
+        color: load_string(data, 23, color_len, 16)?,
+        serial_number: load_string(data, 40, serial_number_len, 16)?,
     };
 
     Ok(Event::GetButtonInfoResponse(evt))
 }
 
+fn marshal_get_button_info_response(evt: &GetButtonInfoResponse) -> Vec<u8> {
+    let mut v = evt.bd_addr.to_vec();
+    v.extend_from_slice(&evt.uuid.0);
+    store_string(&mut v, &evt.color, 16);
+    store_string(&mut v, &evt.serial_number, 16);
+    v
+}
+
 // Sent once if a previously not verified private button is found during the scan. If this is
 // received, tell the user to hold the button down for 7 seconds.
 // Opcode: 15
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ScanWizardFoundPrivateButton {
     pub scan_wizard_id: u32, // Scan wizard id.
 }
@@ -744,6 +1049,7 @@ fn unmarshal_scan_wizard_found_private_button(data: &[u8]) -> Result<Event> {
 // stops scanning internally and instead initiates a connection to this button.
 // Opcode: 16
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ScanWizardFoundPublicButton {
     pub scan_wizard_id: u32, // Scan wizard id.
     pub bd_addr: BdAddr,     // The bluetooth address of the Flic button that was found.
@@ -762,16 +1068,24 @@ fn unmarshal_scan_wizard_found_public_button(data: &[u8]) -> Result<Event> {
     let evt = ScanWizardFoundPublicButton {
         scan_wizard_id: load_u32(data, 0),
         bd_addr: load_bd_addr(data, 4),
-        name: load_string(data, 11, name_len)?,
+        name: load_string(data, 11, name_len, 16)?,
     };
 
     Ok(Event::ScanWizardFoundPublicButton(evt))
 }
 
+fn marshal_scan_wizard_found_public_button(evt: &ScanWizardFoundPublicButton) -> Vec<u8> {
+    let mut v = evt.scan_wizard_id.to_le_bytes().to_vec();
+    v.append(&mut evt.bd_addr.to_vec());
+    store_string(&mut v, &evt.name, 16);
+    v
+}
+
 // Sent when the found button connects for the first time. Now the verification and pairing process
 // will begin.
 // Opcode: 17
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ScanWizardButtonConnected {
     pub scan_wizard_id: u32, // Scan wizard id.
 }
@@ -790,6 +1104,7 @@ fn unmarshal_scan_wizard_button_connected(data: &[u8]) -> Result<Event> {
 // information.
 // Opcode: 18
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ScanWizardCompleted {
     pub scan_wizard_id: u32,      // Scan wizard id.
     pub result: ScanWizardResult, // Result of the scan wizard.
@@ -798,15 +1113,7 @@ pub struct ScanWizardCompleted {
 fn unmarshal_scan_wizard_completed(data: &[u8]) -> Result<Event> {
     check_sz(data, 5)?;
 
-    let result = match FromPrimitive::from_u8(data[4]) {
-        Some(res) => res,
-        None => {
-            return Err(FlicError::Unmarshal(UnmarshalError::BadEnum(
-                data[4],
-                String::from("ScanWizardResult"),
-            )))
-        }
-    };
+    let result = ScanWizardResult::from(data[4]);
 
     let evt = ScanWizardCompleted {
         scan_wizard_id: load_u32(data, 0),
@@ -816,10 +1123,17 @@ fn unmarshal_scan_wizard_completed(data: &[u8]) -> Result<Event> {
     Ok(Event::ScanWizardCompleted(evt))
 }
 
+fn marshal_scan_wizard_completed(evt: &ScanWizardCompleted) -> Vec<u8> {
+    let mut v = evt.scan_wizard_id.to_le_bytes().to_vec();
+    v.push(u8::from(evt.result));
+    v
+}
+
 // Sent as a response to CmdDeleteButton or when a verified button has been deleted from the
 // database.
 // Opcode: 19
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ButtonDeleted {
     pub bd_addr: BdAddr, // The bluetooth device address of the deleted button.
     pub deleted_by_this_client: bool, // Whether or not the client that initiated the deletion was the current client.
@@ -836,14 +1150,22 @@ fn unmarshal_button_deleted(data: &[u8]) -> Result<Event> {
     Ok(Event::ButtonDeleted(evt))
 }
 
+fn marshal_button_deleted(evt: &ButtonDeleted) -> Vec<u8> {
+    let mut v = evt.bd_addr.to_vec();
+    v.push(evt.deleted_by_this_client as u8);
+    v
+}
+
 // Sent to a battery status listener created by CmdCreateBatteryStatusListener in order to indicate
 // the current battery status.
 // Opcode: 20
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BatteryStatus {
     pub listener_id: u32,       // Listener identifier.
     pub battery_percentage: i8, // A value between 0 and 100 that indicates the current battery status. The value can also be -1 if unknown.
-    pub timestamp: SystemTime,  // When the battery status was recorded.
+    #[cfg_attr(feature = "serde", serde(with = "humantime_serde"))]
+    pub timestamp: SystemTime, // When the battery status was recorded.
 }
 
 fn unmarshal_battery_status(data: &[u8]) -> Result<Event> {
@@ -858,18 +1180,200 @@ fn unmarshal_battery_status(data: &[u8]) -> Result<Event> {
     Ok(Event::BatteryStatus(evt))
 }
 
+fn marshal_battery_status(evt: &BatteryStatus) -> Vec<u8> {
+    let mut v = evt.listener_id.to_le_bytes().to_vec();
+    v.push(evt.battery_percentage as u8);
+    store_timestamp(&mut v, evt.timestamp);
+    v
+}
+
+// Not part of the flicd protocol. A Client created with Client::with_reconnect emits this after it
+// has lost its connection to flicd, connected again, and re-sent the commands that created every
+// connection channel, scanner and battery status listener that was still active. It is never
+// received from flicd and has no opcode on the wire.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Reconnected {
+    pub attempts: u32, // The number of connection attempts it took to reconnect.
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    #[should_panic(expected = "BadOpcode")]
-    fn unrecognized_opcode_fails() {
-        // An invalid opcode (0x15) and some random bytes.
+    fn unrecognized_opcode() {
+        // An opcode we don't know about (0x15) and some random bytes.
         let data = vec![0x15, 0x78, 0x56, 0x34, 0x12];
+        let (got, opcode) = unmarshal(&data).expect("failed to unmarshal data");
+        assert_eq!(opcode, Opcode::Unknown);
+        assert_eq!(
+            got,
+            Event::Unknown {
+                opcode: 0x15,
+                body: vec![0x78, 0x56, 0x34, 0x12],
+            }
+        );
+        assert_eq!(marshal(&got), data);
+
+        // Opcodes the client uses for its own events aren't special on the wire.
+        for opcode in [0xFE, 0xFF] {
+            let data = vec![opcode, 0x03, 0x00, 0x00, 0x00];
+            let (got, got_opcode) = unmarshal(&data).expect("failed to unmarshal data");
+            assert_eq!(got_opcode, Opcode::Unknown);
+            assert_eq!(
+                got,
+                Event::Unknown {
+                    opcode,
+                    body: vec![0x03, 0x00, 0x00, 0x00],
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn unrecognized_enum_value() {
+        let data = vec![
+            0x03, // opcode
+            0x78, 0x56, 0x34, 0x12, // conn_id
+            0x20, // removed_reason
+        ];
+        let (got, _) = unmarshal(&data).expect("failed to unmarshal data");
+        assert_eq!(
+            got,
+            Event::ConnectionChannelRemoved(ConnectionChannelRemoved {
+                conn_id: 0x12345678,
+                removed_reason: RemovedReason::Unknown(0x20),
+            })
+        );
+        assert_eq!(marshal(&got), data);
+    }
+
+    #[test]
+    #[should_panic(expected = "EmptyPacket")]
+    fn empty_event_fails() {
+        unmarshal(&[]).expect("failed to unmarshal data");
+    }
+
+    #[test]
+    fn marshal_truncates_long_strings() {
+        let evt = Event::ScanWizardFoundPublicButton(ScanWizardFoundPublicButton {
+            scan_wizard_id: 1,
+            bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            // 15 ASCII bytes followed by a two byte character that doesn't fit.
+            name: String::from("abcdefghijklmnoé"),
+        });
+
+        let data = marshal(&evt);
+        assert_eq!(data.len(), 1 + 27);
+        assert_eq!(data[11], 15);
+
+        let (got, _) = unmarshal(&data).expect("failed to unmarshal truncated string");
+        assert_eq!(
+            got,
+            Event::ScanWizardFoundPublicButton(ScanWizardFoundPublicButton {
+                scan_wizard_id: 1,
+                bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                name: String::from("abcdefghijklmno"),
+            })
+        );
+    }
+
+    #[test]
+    fn marshal_get_info_response_uses_list_length() {
+        let evt = Event::GetInfoResponse(GetInfoResponse {
+            bluetooth_controller_state: BluetoothControllerState::Attached,
+            my_bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            my_bd_addr_type: BdAddrType::PublicBdAddrType,
+            max_pending_connections: 1,
+            max_concurrently_connected_buttons: -1,
+            current_pending_connections: 0,
+            currently_no_space_for_new_connection: false,
+            nb_verified_buttons: 7,
+            bd_addr_of_verified_buttons: vec![BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06])],
+        });
+
+        let data = marshal(&evt);
+        assert_eq!(&data[14..16], &[0x01, 0x00]);
+        assert_eq!(data.len(), 1 + 15 + 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "BadStringLength")]
+    fn string_longer_than_field_fails() {
+        let mut data = vec![0x00; 1 + 32];
+        // The name length says 17 bytes, but the field only has room for 16.
+        data[11] = 17;
         unmarshal(&data).expect("failed to unmarshal data");
     }
 
+    #[test]
+    fn malformed_events_dont_panic() {
+        // Every opcode, with bodies of every length up to a bit past the longest event, filled
+        // with a few patterns that hit the length and enum fields with extreme values.
+        let fills: &[fn(usize) -> u8] = &[|_| 0x00, |_| 0xFF, |i| i as u8, |i| (i * 37) as u8];
+        for opcode in 0..=21 {
+            for len in 0..64 {
+                for fill in fills {
+                    let mut data = vec![opcode];
+                    data.extend((0..len).map(fill));
+                    let _ = unmarshal(&data);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_json_shape() {
+        let cases = vec![
+            (
+                Event::ButtonUpOrDown(ButtonUpOrDown {
+                    conn_id: 7,
+                    click_type: ClickType::ButtonDown,
+                    was_queued: false,
+                    time_diff: 0,
+                }),
+                r#"{"type":"button_up_or_down","conn_id":7,"click_type":"button_down","was_queued":false,"time_diff":0}"#,
+            ),
+            (
+                Event::BatteryStatus(BatteryStatus {
+                    listener_id: 1,
+                    battery_percentage: 96,
+                    timestamp: UNIX_EPOCH + Duration::from_secs(1587654310),
+                }),
+                r#"{"type":"battery_status","listener_id":1,"battery_percentage":96,"timestamp":"2020-04-23T15:05:10Z"}"#,
+            ),
+            (
+                Event::ButtonDeleted(ButtonDeleted {
+                    bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                    deleted_by_this_client: true,
+                }),
+                r#"{"type":"button_deleted","bd_addr":"06:05:04:03:02:01","deleted_by_this_client":true}"#,
+            ),
+            (
+                Event::ScanWizardCompleted(ScanWizardCompleted {
+                    scan_wizard_id: 2,
+                    result: ScanWizardResult::Unknown(42),
+                }),
+                r#"{"type":"scan_wizard_completed","scan_wizard_id":2,"result":{"unknown":42}}"#,
+            ),
+            (
+                Event::Unknown {
+                    opcode: 0x15,
+                    body: vec![1, 2],
+                },
+                r#"{"type":"unknown","opcode":21,"body":[1,2]}"#,
+            ),
+        ];
+
+        for (evt, want) in cases {
+            let json = serde_json::to_string(&evt).unwrap();
+            assert_eq!(json, want);
+            assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), evt);
+        }
+    }
+
     macro_rules! unmarshal_tests {
         ($($name:ident: $value:expr,)*) => {
     $(
@@ -878,6 +1382,7 @@ mod tests {
             let (data, want): (&[u8], Event) = $value;
             let (got, _) = unmarshal(data).expect("failed to unmarshal valid data");
             assert_eq!(want, got);
+            assert_eq!(marshal(&got), data);
         }
 
     )*
@@ -886,7 +1391,7 @@ mod tests {
 
     unmarshal_tests! {
         unmarshal_advertisement_packet: (
-            &vec![
+            &[
                 0x00, // opcode
                 0x78, 0x56, 0x34, 0x12, // scan_id
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
@@ -910,7 +1415,7 @@ mod tests {
             })
             ),
         unmarshal_create_connection_channel_response: (
-            &vec![
+            &[
                 0x01, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x01, // error
@@ -923,7 +1428,7 @@ mod tests {
             })
             ),
         unmarshal_connection_status_changed: (
-            &vec![
+            &[
                 0x02, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x01, // connection_status
@@ -936,7 +1441,7 @@ mod tests {
             })
             ),
         unmarshal_connection_channel_removed: (
-            &vec![
+            &[
                 0x03, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x03, // removed_reason
@@ -947,7 +1452,7 @@ mod tests {
             })
             ),
         unmarshal_button_up_or_down: (
-            &vec![
+            &[
                 0x04, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x01, // click_type
@@ -962,7 +1467,7 @@ mod tests {
             })
             ),
         unmarshal_button_click_or_hold: (
-            &vec![
+            &[
                 0x05, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x02, // click_type
@@ -977,7 +1482,7 @@ mod tests {
             })
             ),
         unmarshal_button_single_or_double_click: (
-            &vec![
+            &[
                 0x06, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x04, // click_type
@@ -992,7 +1497,7 @@ mod tests {
             })
             ),
         unmarshal_button_single_or_double_click_or_hold: (
-            &vec![
+            &[
                 0x07, // opcode
                 0x78, 0x56, 0x34, 0x12, // conn_id
                 0x05, // click_type
@@ -1007,7 +1512,7 @@ mod tests {
             })
             ),
         unmarshal_new_verified_button: (
-            &vec![
+            &[
                 0x08, // opcode
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
             ],
@@ -1016,7 +1521,7 @@ mod tests {
             })
             ),
         unmarshal_get_info_response: (
-            &vec![
+            &[
                 0x09, // opcode
                 0x02, // bluetooth_controller_state
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // my_bd_addr
@@ -1049,7 +1554,7 @@ mod tests {
             })
             ),
         unmarshal_no_space_for_new_connection: (
-            &vec![
+            &[
                 0x0A, // opcode
                 0x10, // max_concurrently_connected_buttons
             ],
@@ -1058,7 +1563,7 @@ mod tests {
             })
             ),
         unmarshal_got_space_for_new_connection: (
-            &vec![
+            &[
                 0x0B, // opcode
                 0x11, // max_concurrently_connected_buttons
             ],
@@ -1067,7 +1572,7 @@ mod tests {
             })
             ),
         unmarshal_bluetooth_controller_state_change: (
-            &vec![
+            &[
                 0x0C, // opcode
                 0x01, // state
             ],
@@ -1076,7 +1581,7 @@ mod tests {
             })
             ),
         unmarshal_ping_response: (
-            &vec![
+            &[
                 0x0D, // opcode
                 0x78, 0x56, 0x34, 0x12, // ping_id
             ],
@@ -1085,7 +1590,7 @@ mod tests {
             })
             ),
         unmarshal_get_button_info_response: (
-            &vec![
+            &[
                 0x0E, // opcode
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x10, // uuid
@@ -1102,7 +1607,7 @@ mod tests {
             })
             ),
         unmarshal_scan_wizard_found_private_button: (
-            &vec![
+            &[
                 0x0F, // opcode
                 0x78, 0x56, 0x34, 0x12, // scan_wizard_id
             ],
@@ -1111,7 +1616,7 @@ mod tests {
             })
             ),
         unmarshal_scan_wizard_found_public_button: (
-            &vec![
+            &[
                 0x10, // opcode
                 0x78, 0x56, 0x34, 0x12, // scan_wizard_id
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
@@ -1125,7 +1630,7 @@ mod tests {
             })
             ),
         unmarshal_scan_wizard_button_connected: (
-            &vec![
+            &[
                 0x11, // opcode
                 0x78, 0x56, 0x34, 0x12, // scan_wizard_id
             ],
@@ -1134,7 +1639,7 @@ mod tests {
             })
             ),
         unmarshal_scan_wizard_completed: (
-            &vec![
+            &[
                 0x12, // opcode
                 0x78, 0x56, 0x34, 0x12, // scan_wizard_id
                 0x03, // result
@@ -1145,7 +1650,7 @@ mod tests {
             })
             ),
         unmarshal_button_deleted: (
-            &vec![
+            &[
                 0x13, // opcode
                 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // bd_addr
                 0x01, // deleted_by_this_client
@@ -1156,7 +1661,7 @@ mod tests {
             })
             ),
         unmarshal_battery_status: (
-            &vec![
+            &[
                 0x14, // opcode
                 0x78, 0x56, 0x34, 0x12, // listener_id
                 0x60, // battery_percentage
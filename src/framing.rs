@@ -0,0 +1,198 @@
+// Every packet exchanged with flicd, in either direction, is a little endian u16 length header
+// followed by that many bytes: an opcode and the body.
+
+use std::io::{self, ErrorKind, Read};
+
+use crate::commands::Command;
+use crate::error::FlicError;
+use crate::Result;
+
+// Size of the length header that precedes every packet.
+pub(crate) const HEADER_LEN: usize = 2;
+
+// How much we ask the stream for at a time. Most packets are much smaller than this.
+const READ_CHUNK: usize = 512;
+
+// Returns the complete packet for a command, length header included.
+pub(crate) fn encode<C: Command + ?Sized>(cmd: &C) -> Vec<u8> {
+    let mut body = cmd.marshal();
+    // Prepend opcode
+    body.insert(0, cmd.opcode());
+
+    // Get the length, and prepend that as the length header, little endian.
+    let len = body.len().to_le_bytes();
+    body.insert(0, len[1]);
+    body.insert(0, len[0]);
+
+    body
+}
+
+// If buf starts with a complete packet, removes it and returns everything after the length header.
+// Otherwise leaves buf untouched.
+pub(crate) fn decode(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+
+    let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+    if buf.len() < HEADER_LEN + len {
+        return None;
+    }
+
+    let frame = buf[HEADER_LEN..HEADER_LEN + len].to_vec();
+    buf.drain(..HEADER_LEN + len);
+    Some(frame)
+}
+
+// Reads packets from a stream, keeping whatever part of a packet has arrived between calls, so a
+// read that times out part way through a packet can pick up where it left off.
+pub(crate) struct FrameReader<R> {
+    inner: R,
+    // Bytes read that don't make up a complete packet yet.
+    buf: Vec<u8>,
+    // Set once a read fails part way through a packet. We no longer know where the next packet
+    // starts, so every read after that fails too.
+    broken: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub(crate) fn new(inner: R) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            buf: Vec::new(),
+            broken: false,
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    // Returns the next packet, minus the length header, or None if the stream timed out first.
+    pub(crate) fn read_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.broken {
+            return Err(FlicError::BrokenStream);
+        }
+
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            if let Some(frame) = decode(&mut self.buf) {
+                return Ok(Some(frame));
+            }
+
+            match self.inner.read(&mut chunk) {
+                Ok(0) if self.buf.is_empty() => {
+                    return Err(FlicError::FlicD(io::Error::from(ErrorKind::UnexpectedEof)))
+                }
+                Ok(n) if n > 0 => self.buf.extend_from_slice(&chunk[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if err.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) if self.buf.is_empty() => return Err(FlicError::FlicD(err)),
+                // The stream ended or failed in the middle of a packet.
+                _ => {
+                    self.broken = true;
+                    return Err(FlicError::BrokenStream);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CreateScanner;
+    use std::collections::VecDeque;
+
+    // A stream that hands out the given reads one at a time, and then reaches the end.
+    struct Script(VecDeque<io::Result<Vec<u8>>>);
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(data)) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Some(Err(err)) => Err(err),
+                None => Ok(0),
+            }
+        }
+    }
+
+    fn timeout() -> io::Result<Vec<u8>> {
+        Err(io::Error::from(ErrorKind::WouldBlock))
+    }
+
+    #[test]
+    fn encode_prepends_length_and_opcode() {
+        let cmd = CreateScanner {
+            scan_id: 0x12345678,
+        };
+        assert_eq!(
+            encode(&cmd),
+            vec![
+                0x05, 0x00, // length
+                0x01, // opcode
+                0x78, 0x56, 0x34, 0x12, // scan_id
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_waits_for_complete_packet() {
+        let mut buf = vec![0x05, 0x00, 0x0D, 0x78];
+        assert_eq!(decode(&mut buf), None);
+        assert_eq!(buf.len(), 4);
+
+        buf.extend_from_slice(&[0x56, 0x34, 0x12, 0x02]);
+        assert_eq!(decode(&mut buf), Some(vec![0x0D, 0x78, 0x56, 0x34, 0x12]));
+        assert_eq!(buf, vec![0x02]);
+    }
+
+    #[test]
+    fn frame_reader_keeps_partial_frames() {
+        let mut r = FrameReader::new(Script(VecDeque::from(vec![
+            Ok(vec![0x02]),
+            timeout(),
+            Ok(vec![0x00, 0x0D]),
+            timeout(),
+            // The end of the first frame, all of the second and the start of a third.
+            Ok(vec![0x01, 0x01, 0x00, 0x0A, 0x03]),
+            timeout(),
+        ])));
+
+        assert_eq!(r.read_frame().unwrap(), None);
+        assert_eq!(r.read_frame().unwrap(), None);
+        assert_eq!(r.read_frame().unwrap(), Some(vec![0x0D, 0x01]));
+        assert_eq!(r.read_frame().unwrap(), Some(vec![0x0A]));
+        assert_eq!(r.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn frame_reader_end_of_stream() {
+        let mut r = FrameReader::new(Script(VecDeque::from(vec![Ok(vec![0x01, 0x00, 0x0D])])));
+        assert_eq!(r.read_frame().unwrap(), Some(vec![0x0D]));
+        match r.read_frame() {
+            Err(FlicError::FlicD(err)) => assert_eq!(err.kind(), ErrorKind::UnexpectedEof),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frame_reader_broken_mid_frame() {
+        let mut r = FrameReader::new(Script(VecDeque::from(vec![
+            Ok(vec![0x05, 0x00, 0x0D]),
+            Err(io::Error::from(ErrorKind::ConnectionReset)),
+            Ok(vec![0x78, 0x56, 0x34, 0x12]),
+        ])));
+
+        for _ in 0..2 {
+            match r.read_frame() {
+                Err(FlicError::BrokenStream) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
@@ -1,29 +1,76 @@
 #[macro_use]
 extern crate num_derive;
 
+use std::cmp::Ordering;
 use std::fmt::{self, Formatter};
+use std::str::FromStr;
 
 pub mod commands;
 pub mod enums;
 pub mod events;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
+#[cfg(feature = "async")]
+mod async_client;
+mod capture;
+mod channel;
 mod client;
 mod error;
+mod framing;
 mod manager;
+mod registry;
+mod rules;
+mod scan_wizard;
+mod transport;
+mod worker_pool;
 
-pub use client::Client;
+#[cfg(feature = "async")]
+pub use async_client::{AsyncClient, EventStream};
+pub use capture::{CaptureReader, CaptureWriter, Direction, Record, Replay};
+pub use channel::ConnectionChannel;
+pub use client::{Client, ReconnectPolicy};
 pub use error::FlicError;
-pub use manager::Manager;
+pub use manager::{Manager, StopHandle, Subscription};
+pub use registry::{RegisteredButton, Registry};
+pub use rules::{Action, ButtonSelector, Click, Rule, Rules};
+pub use scan_wizard::{ScanWizard, ScanWizardProgress};
+pub use worker_pool::{Overflow, WorkerPool};
 
 pub type Result<T> = std::result::Result<T, error::FlicError>;
 
 /// Flic's representation of a Bluetooth address, stored as 6 little endian-encoded bytes.
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BdAddr([u8; 6]);
 
 impl BdAddr {
-    fn to_vec(&self) -> Vec<u8> {
-        self.0.clone().to_vec()
+    /// Creates an address from its bytes as they're sent to flicd, which is the reverse of the
+    /// order they're displayed in.
+    pub fn from_bytes(bytes: [u8; 6]) -> BdAddr {
+        BdAddr(bytes)
+    }
+
+    /// The address's bytes as they're sent to flicd, which is the reverse of the order they're
+    /// displayed in.
+    pub fn as_bytes(&self) -> &[u8; 6] {
+        &self.0
+    }
+
+    fn to_vec(self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+// Ordered the same way as the displayed form, most significant byte first.
+impl Ord for BdAddr {
+    fn cmp(&self, other: &BdAddr) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for BdAddr {
+    fn partial_cmp(&self, other: &BdAddr) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
@@ -48,9 +95,45 @@ impl fmt::Display for BdAddr {
     }
 }
 
-#[derive(PartialEq)]
+// Parses a BdAddr in the same colon-separated form it's displayed in, e.g. "80:e4:da:71:12:34".
+impl FromStr for BdAddr {
+    type Err = FlicError;
+
+    fn from_str(s: &str) -> Result<BdAddr> {
+        let invalid = || FlicError::Generic(format!("invalid bluetooth address {:?}", s));
+
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 6 {
+            return Err(invalid());
+        }
+
+        let mut bd_addr = [0u8; 6];
+        for (i, part) in parts.iter().rev().enumerate() {
+            if part.len() != 2 || !part.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            bd_addr[i] = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        Ok(BdAddr(bd_addr))
+    }
+}
+
+/// The 128-bit identifier each Flic button has.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Uuid([u8; 16]);
 
+impl Uuid {
+    /// Creates a uuid from its bytes, in the order they're displayed in.
+    pub fn from_bytes(bytes: [u8; 16]) -> Uuid {
+        Uuid(bytes)
+    }
+
+    /// The uuid's bytes, in the order they're displayed in.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
 impl fmt::Debug for Uuid {
     fn fmt(&self, f: &mut Formatter) -> std::result::Result<(), fmt::Error> {
         <Uuid as fmt::Display>::fmt(self, f)
@@ -71,6 +154,57 @@ impl fmt::Display for Uuid {
     }
 }
 
+// Parses a Uuid in the same hyphenated form it's displayed in.
+impl FromStr for Uuid {
+    type Err = FlicError;
+
+    fn from_str(s: &str) -> Result<Uuid> {
+        let invalid = || FlicError::Generic(format!("invalid uuid {:?}", s));
+
+        let groups: Vec<&str> = s.split('-').collect();
+        let lens: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+        if lens != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+
+        let bytes = hex::decode(groups.concat()).map_err(|_| invalid())?;
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&bytes);
+        Ok(Uuid(uuid))
+    }
+}
+
+// Both are (de)serialized as strings in the same form they're displayed in, so they can be used as
+// keys in maps and read from config files.
+#[cfg(feature = "serde")]
+macro_rules! serde_as_string {
+    ($($name:ident),*) => {
+        $(
+            impl serde::Serialize for $name {
+                fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+                where
+                    S: serde::Serializer,
+                {
+                    serializer.collect_str(self)
+                }
+            }
+
+            impl<'de> serde::Deserialize<'de> for $name {
+                fn deserialize<D>(deserializer: D) -> std::result::Result<$name, D::Error>
+                where
+                    D: serde::Deserializer<'de>,
+                {
+                    let s = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+                    s.parse().map_err(serde::de::Error::custom)
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "serde")]
+serde_as_string!(BdAddr, Uuid);
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,4 +226,73 @@ mod tests {
             "00010203-0405-0607-0809-0a0b0c0d0e0f"
         );
     }
+
+    #[test]
+    fn bd_addr_from_str() {
+        let bd_addr: BdAddr = "08:09:0a:0b:0c:0d".parse().unwrap();
+        assert_eq!(bd_addr, BdAddr([0x0d, 0x0c, 0x0b, 0x0a, 0x09, 0x08]));
+
+        for bad in &[
+            "",
+            "08:09:0a:0b:0c",
+            "08:09:0a:0b:0c:0d:0e",
+            "08:09:0a:0b:0c:zz",
+            "8:9:a:b:c:d",
+        ] {
+            assert!(bad.parse::<BdAddr>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn uuid_from_str() {
+        let uuid: Uuid = "00010203-0405-0607-0809-0a0b0c0d0e0f".parse().unwrap();
+        assert_eq!(format!("{}", uuid), "00010203-0405-0607-0809-0a0b0c0d0e0f");
+
+        for bad in &[
+            "",
+            "000102030405060708090a0b0c0d0e0f",
+            "00010203-0405-0607-0809-0a0b0c0d0e0g",
+        ] {
+            assert!(bad.parse::<Uuid>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn bytes() {
+        let bytes = [0x0d, 0x0c, 0x0b, 0x0a, 0x09, 0x08];
+        let bd_addr = BdAddr::from_bytes(bytes);
+        assert_eq!(bd_addr.to_string(), "08:09:0a:0b:0c:0d");
+        assert_eq!(bd_addr.as_bytes(), &bytes);
+
+        let uuid: Uuid = "00010203-0405-0607-0809-0a0b0c0d0e0f".parse().unwrap();
+        assert_eq!(Uuid::from_bytes(*uuid.as_bytes()), uuid);
+        assert_eq!(uuid.as_bytes()[1], 0x01);
+    }
+
+    #[test]
+    fn bd_addr_order() {
+        let low: BdAddr = "00:00:00:00:00:02".parse().unwrap();
+        let high: BdAddr = "01:00:00:00:00:01".parse().unwrap();
+        assert!(low < high);
+
+        let mut addrs = vec![high, low];
+        addrs.sort();
+        assert_eq!(addrs, [low, high]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_strings() {
+        let bd_addr: BdAddr = "08:09:0a:0b:0c:0d".parse().unwrap();
+        let json = serde_json::to_string(&bd_addr).unwrap();
+        assert_eq!(json, r#""08:09:0a:0b:0c:0d""#);
+        assert_eq!(serde_json::from_str::<BdAddr>(&json).unwrap(), bd_addr);
+
+        let uuid: Uuid = "00010203-0405-0607-0809-0a0b0c0d0e0f".parse().unwrap();
+        let json = serde_json::to_string(&uuid).unwrap();
+        assert_eq!(json, r#""00010203-0405-0607-0809-0a0b0c0d0e0f""#);
+        assert_eq!(serde_json::from_str::<Uuid>(&json).unwrap(), uuid);
+
+        assert!(serde_json::from_str::<BdAddr>(r#""not an address""#).is_err());
+    }
 }
@@ -1,48 +1,789 @@
-use crate::events;
+use crate::events::{self, EventType};
+use crate::BdAddr;
 use crate::Result;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
 
 use crate::client::Client;
+use crate::error::FlicError;
+use crate::worker_pool::{Pool, WorkerPool};
 
-type Handler = Box<dyn Fn(&events::Event) + Send + 'static>;
+// How often start checks whether it's been asked to stop, while waiting for events.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type HandlerFn = Arc<dyn Fn(&events::Event) + Send + Sync + 'static>;
+type ErrorFn = Arc<dyn Fn(&FlicError) + Send + Sync + 'static>;
+type Handlers = Mutex<HashMap<events::Opcode, Vec<Handler>>>;
+
+// A registered handler, and which events for its opcode it wants.
+struct Handler {
+    filter: Filter,
+    f: HandlerFn,
+    // Shared with the handler's Subscription. Cleared when it's unsubscribed, or after the first
+    // call for a one-shot handler, and then the handler is removed the next time we get the chance.
+    active: Arc<AtomicBool>,
+    once: bool,
+}
+
+enum Filter {
+    All,
+    ConnId(u32),
+    BdAddr(BdAddr),
+}
+
+impl Filter {
+    fn matches(&self, evt: &events::Event, client: &Client) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::ConnId(conn_id) => evt.conn_id() == Some(*conn_id),
+            Filter::BdAddr(bd_addr) => match (evt.bd_addr(), evt.conn_id()) {
+                (Some(got), _) => got == bd_addr,
+                (None, Some(conn_id)) => client.channel_bd_addr(conn_id).as_ref() == Some(bd_addr),
+                (None, None) => false,
+            },
+        }
+    }
+}
 
 pub struct Manager {
     pub client: Client,
-    handlers: Mutex<HashMap<events::Opcode, Vec<Handler>>>,
+    handlers: Arc<Handlers>,
+    stop: Arc<StopState>,
+    // Where handlers run, if not on the thread calling start.
+    pool: Option<Pool>,
+    // Called with errors that don't stop start, see on_error.
+    on_error: Mutex<Option<ErrorFn>>,
+}
+
+#[derive(Default)]
+struct StopState {
+    stopping: AtomicBool,
+    clean_up: AtomicBool,
+}
+
+/// Makes `Manager::start` return, from another thread or a handler. Once stopped, a manager stays
+/// stopped, and its client is closed.
+#[derive(Clone)]
+pub struct StopHandle {
+    state: Arc<StopState>,
+}
+
+impl StopHandle {
+    pub fn stop(&self) {
+        self.state.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Like `stop`, but first removes every connection channel, scanner and battery status
+    /// listener the client created, so flicd doesn't keep them around.
+    pub fn stop_and_clean_up(&self) {
+        self.state.clean_up.store(true, Ordering::SeqCst);
+        self.stop();
+    }
+}
+
+/// A registered handler. Dropping it, or calling `unsubscribe`, removes the handler, and `detach`
+/// keeps it for as long as the `Manager` is around.
+#[must_use = "the handler is removed as soon as the subscription is dropped"]
+pub struct Subscription {
+    handlers: Weak<Handlers>,
+    opcode: events::Opcode,
+    active: Arc<AtomicBool>,
+    detached: bool,
+}
+
+impl Subscription {
+    pub fn unsubscribe(self) {}
+
+    /// Keeps the handler registered for the rest of the manager's life.
+    pub fn detach(mut self) {
+        self.detached = true;
+    }
+
+    /// Whether the handler will still be called. This turns false once a one-shot handler fires.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
+        self.active.store(false, Ordering::SeqCst);
+
+        // If the lock is busy, e.g. because a handler is unsubscribing itself, the handler is
+        // cleaned up on the next event for its opcode instead.
+        let handlers = match self.handlers.upgrade() {
+            Some(handlers) => handlers,
+            None => return,
+        };
+        let mut handlers = match handlers.try_lock() {
+            Ok(handlers) => handlers,
+            Err(_) => return,
+        };
+        if let Some(v) = handlers.get_mut(&self.opcode) {
+            v.retain(|h| !Arc::ptr_eq(&h.active, &self.active));
+        }
+    }
 }
 
 impl Manager {
     pub fn new(host: &str) -> Result<Manager> {
-        let client = Client::new(host)?;
-        Ok(Manager {
+        Ok(Manager::with_client(Client::new(host)?))
+    }
+
+    // Creates a manager around an existing client, e.g. one created with Client::with_reconnect.
+    pub fn with_client(client: Client) -> Manager {
+        Manager {
             client,
-            handlers: Mutex::new(HashMap::new()),
-        })
+            handlers: Arc::new(Mutex::new(HashMap::new())),
+            stop: Arc::new(StopState::default()),
+            pool: None,
+            on_error: Mutex::new(None),
+        }
     }
 
-    pub fn register_handler<F>(&self, opcode: events::Opcode, f: F)
+    /// Creates a manager that calls handlers on a pool of worker threads, so a slow handler
+    /// doesn't hold up reading events or other handlers.
+    pub fn with_worker_pool(client: Client, pool: WorkerPool) -> Manager {
+        Manager {
+            pool: Some(Pool::start(&pool)),
+            ..Manager::with_client(client)
+        }
+    }
+
+    pub fn register_handler<F>(&self, opcode: events::Opcode, f: F) -> Subscription
     where
-        F: Fn(&events::Event) + Send + 'static,
+        F: Fn(&events::Event) + Send + Sync + 'static,
     {
+        self.add_handler(opcode, Filter::All, false, Arc::new(f))
+    }
+
+    /// Registers a handler that's only called for the first event with `opcode`, and then
+    /// removes itself.
+    pub fn register_handler_once<F>(&self, opcode: events::Opcode, f: F) -> Subscription
+    where
+        F: FnOnce(&events::Event) + Send + 'static,
+    {
+        let f = Mutex::new(Some(f));
+        let f = move |evt: &events::Event| {
+            if let Some(f) = f.lock().unwrap().take() {
+                f(evt);
+            }
+        };
+        self.add_handler(opcode, Filter::All, true, Arc::new(f))
+    }
+
+    /// Registers a handler for one type of event, which gets the event struct itself, e.g.
+    /// `manager.on::<ButtonSingleOrDoubleClickOrHold>(|evt| println!("{:?}", evt.click_type))`.
+    pub fn on<T: EventType>(&self, f: impl Fn(&T) + Send + Sync + 'static) -> Subscription {
+        self.add_typed_handler(Filter::All, false, f)
+    }
+
+    /// Like `on`, but only for events about the connection channel `conn_id`.
+    pub fn on_conn<T: EventType>(
+        &self,
+        conn_id: u32,
+        f: impl Fn(&T) + Send + Sync + 'static,
+    ) -> Subscription {
+        self.add_typed_handler(Filter::ConnId(conn_id), false, f)
+    }
+
+    /// Like `on`, but only for events about the button `bd_addr`. That's either events that
+    /// include the address, or events about a connection channel to the button that was created
+    /// through this manager's client, up to and including the `ConnectionChannelRemoved` (or
+    /// failed `CreateConnectionChannelResponse`) that ends it.
+    pub fn on_button<T: EventType>(
+        &self,
+        bd_addr: &BdAddr,
+        f: impl Fn(&T) + Send + Sync + 'static,
+    ) -> Subscription {
+        self.add_typed_handler(Filter::BdAddr(*bd_addr), false, f)
+    }
+
+    /// Like `on`, but the handler is only called for the first event, and then removes itself.
+    pub fn once<T: EventType>(&self, f: impl FnOnce(&T) + Send + 'static) -> Subscription {
+        let f = Mutex::new(Some(f));
+        let f = move |evt: &T| {
+            if let Some(f) = f.lock().unwrap().take() {
+                f(evt);
+            }
+        };
+        self.add_typed_handler(Filter::All, true, f)
+    }
+
+    fn add_typed_handler<T: EventType>(
+        &self,
+        filter: Filter,
+        once: bool,
+        f: impl Fn(&T) + Send + Sync + 'static,
+    ) -> Subscription {
+        let f = move |evt: &events::Event| {
+            if let Some(evt) = T::from_event(evt) {
+                f(evt);
+            }
+        };
+        self.add_handler(T::OPCODE, filter, once, Arc::new(f))
+    }
+
+    fn add_handler(
+        &self,
+        opcode: events::Opcode,
+        filter: Filter,
+        once: bool,
+        f: HandlerFn,
+    ) -> Subscription {
+        let active = Arc::new(AtomicBool::new(true));
+
         let mut handlers = self.handlers.lock().unwrap();
-        let v = handlers.entry(opcode).or_insert(vec![]);
-        v.push(Box::new(f));
+        let v = handlers.entry(opcode.clone()).or_insert(vec![]);
+        v.push(Handler {
+            filter,
+            f,
+            active: Arc::clone(&active),
+            once,
+        });
+
+        Subscription {
+            handlers: Arc::downgrade(&self.handlers),
+            opcode,
+            active,
+            detached: false,
+        }
+    }
+
+    /// Registers a function to be called with errors that `start` carries on after: a
+    /// `FlicError::Unmarshal` for each packet from flicd that couldn't be decoded, and
+    /// `FlicError::HandlerQueueFull` when a handler call is dropped because the worker pool's
+    /// queue is full. Replaces any function registered before.
+    pub fn on_error<F>(&self, f: F)
+    where
+        F: Fn(&FlicError) + Send + Sync + 'static,
+    {
+        *self.on_error.lock().unwrap() = Some(Arc::new(f));
+    }
+
+    // Hands an error that doesn't stop start to the on_error function, if there is one.
+    fn report(&self, err: FlicError) {
+        let f = self.on_error.lock().unwrap().clone();
+        if let Some(f) = f {
+            f(&err);
+        }
+    }
+
+    pub fn stop_handle(&self) -> StopHandle {
+        StopHandle {
+            state: Arc::clone(&self.stop),
+        }
     }
 
+    /// Reads events and calls the handlers for them until the manager is stopped with a
+    /// `StopHandle`, or reading fails.
     pub fn start(&self) -> Result<()> {
         loop {
-            let (evt, opcode) = self.client.next_event()?;
+            if self.stop.stopping.load(Ordering::SeqCst) {
+                if self.stop.clean_up.load(Ordering::SeqCst) {
+                    self.client.clean_up()?;
+                }
+                return self.client.close();
+            }
+
+            let (evt, opcode) = match self
+                .client
+                .next_event_with_timeout(Some(STOP_POLL_INTERVAL))
+            {
+                Ok(Some(evt)) => evt,
+                Ok(None) => continue,
+                // The packet was read in full, so the ones after it are fine. Report it rather than
+                // stopping every handler.
+                Err(err @ FlicError::Unmarshal(_)) => {
+                    self.report(err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            // Pick out the handlers to call, and then let go of the lock before calling them, so
+            // handlers can register and unsubscribe handlers themselves.
+            let calls: Vec<HandlerFn> = {
+                let mut handlers = self.handlers.lock().unwrap();
+                let handlers = match handlers.get_mut(&opcode) {
+                    Some(handlers) => handlers,
+                    None => continue,
+                };
 
-            let handlers = self.handlers.lock().unwrap();
-            let handlers = match handlers.get(&opcode) {
-                Some(handlers) => handlers,
-                None => continue,
+                let calls = handlers
+                    .iter()
+                    .filter(|h| {
+                        h.active.load(Ordering::SeqCst) && h.filter.matches(&evt, &self.client)
+                    })
+                    .map(|h| {
+                        if h.once {
+                            h.active.store(false, Ordering::SeqCst);
+                        }
+                        Arc::clone(&h.f)
+                    })
+                    .collect();
+                handlers.retain(|h| h.active.load(Ordering::SeqCst));
+                calls
             };
 
-            for event_fn in handlers {
-                event_fn(&evt);
+            match &self.pool {
+                None => {
+                    for f in calls {
+                        f(&evt);
+                    }
+                }
+                Some(pool) => {
+                    let evt = Arc::new(evt);
+                    for f in calls {
+                        let evt = Arc::clone(&evt);
+                        // Only this call is dropped, the other handlers and later events are fine.
+                        if let Err(err) = pool.submit(Box::new(move || f(&evt))) {
+                            self.report(err);
+                        }
+                    }
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{self, AnyCommand, Opcode};
+    use crate::enums::{
+        ConnectionStatus, CreateConnectionChannelError, LatencyMode, RemovedReason,
+    };
+    use crate::error::UnmarshalError;
+    use crate::events::{
+        ButtonDeleted, ButtonUpOrDown, ConnectionChannelRemoved, CreateConnectionChannelResponse,
+        Event, PingResponse,
+    };
+    use crate::testing::{bd_addr, button_down, FakeFlicd, TIMEOUT};
+    use crate::worker_pool::Overflow;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    fn start(fake: &FakeFlicd) -> Arc<Manager> {
+        let manager = Arc::new(Manager::new(&fake.addr()).unwrap());
+        let m = Arc::clone(&manager);
+        thread::spawn(move || m.start());
+        manager
+    }
+
+    #[test]
+    fn typed_handlers() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        let _downs = manager.on::<ButtonUpOrDown>(move |evt| tx.send(evt.conn_id).unwrap());
+        let _pings = manager.on::<PingResponse>(move |evt| tx2.send(evt.ping_id).unwrap());
+
+        fake.send_event(&button_down(3)).unwrap();
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 9 }))
+            .unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(3));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(9));
+    }
+
+    #[test]
+    fn unknown_and_malformed_events() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        let _unknown = manager.register_handler(events::Opcode::Unknown, move |evt| {
+            if let Event::Unknown { opcode, .. } = evt {
+                tx.send(u32::from(*opcode)).unwrap();
+            }
+        });
+        let _downs = manager.on::<ButtonUpOrDown>(move |evt| tx2.send(evt.conn_id).unwrap());
+        let (err_tx, err_rx) = mpsc::channel();
+        let err_tx = Mutex::new(err_tx);
+        manager.on_error(move |err| {
+            let unmarshal = matches!(err, FlicError::Unmarshal(UnmarshalError::BadLength(..)));
+            err_tx.lock().unwrap().send(unmarshal).unwrap();
+        });
+
+        // An event from a newer flicd, and a button event that's a byte short.
+        fake.send_frame(&[0x30, 0x01, 0x02]).unwrap();
+        fake.send_frame(&events::marshal(&button_down(3))[..10])
+            .unwrap();
+        fake.send_event(&button_down(4)).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(0x30));
+        assert_eq!(err_rx.recv_timeout(TIMEOUT), Ok(true));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(4));
+    }
+
+    #[test]
+    fn filters() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::CreateConnectionChannel, |cmd| match cmd {
+            AnyCommand::CreateConnectionChannel(cmd) => {
+                vec![Event::CreateConnectionChannelResponse(
+                    CreateConnectionChannelResponse {
+                        conn_id: cmd.conn_id,
+                        error: CreateConnectionChannelError::NoError,
+                        connection_status: ConnectionStatus::Disconnected,
+                    },
+                )]
+            }
+            _ => vec![],
+        });
+        let manager = start(&fake);
+        let channel = manager
+            .client
+            .connect(&bd_addr(), LatencyMode::Normal, 511)
+            .unwrap();
+        let conn_id = channel.conn_id();
+        // Not one of ours, so we don't know which button it is.
+        manager
+            .client
+            .send_command(commands::CreateConnectionChannel {
+                conn_id: 100,
+                bd_addr: BdAddr([0x06, 0x05, 0x04, 0x03, 0x02, 0x01]),
+                latency_mode: LatencyMode::Normal,
+                auto_disconnect_time: 511,
+            })
+            .unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let (tx2, tx3) = (tx.clone(), tx.clone());
+        let _conn = manager.on_conn::<ButtonUpOrDown>(conn_id, move |evt| {
+            tx.send(format!("conn {}", evt.conn_id)).unwrap()
+        });
+        let _button = manager.on_button::<ButtonUpOrDown>(&bd_addr(), move |evt| {
+            tx2.send(format!("button {}", evt.conn_id)).unwrap()
+        });
+        let _deleted = manager.on_button::<ButtonDeleted>(&bd_addr(), move |evt| {
+            tx3.send(format!("deleted {}", evt.bd_addr)).unwrap()
+        });
+
+        fake.send_event(&button_down(100)).unwrap();
+        fake.send_event(&Event::ButtonDeleted(ButtonDeleted {
+            bd_addr: BdAddr([0x06, 0x05, 0x04, 0x03, 0x02, 0x01]),
+            deleted_by_this_client: false,
+        }))
+        .unwrap();
+        fake.send_event(&button_down(conn_id)).unwrap();
+        fake.send_event(&Event::ButtonDeleted(ButtonDeleted {
+            bd_addr: bd_addr(),
+            deleted_by_this_client: false,
+        }))
+        .unwrap();
+
+        let mut got: Vec<String> = (0..3).map(|_| rx.recv_timeout(TIMEOUT).unwrap()).collect();
+        got.sort();
+        assert_eq!(
+            got,
+            vec![
+                format!("button {}", conn_id),
+                format!("conn {}", conn_id),
+                format!("deleted {}", bd_addr()),
+            ]
+        );
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn button_filter_sees_channel_end() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        let _removed = manager.on_button::<ConnectionChannelRemoved>(&bd_addr(), move |evt| {
+            tx.send(format!("removed {}", evt.conn_id)).unwrap()
+        });
+        let _failed = manager
+            .on_button::<CreateConnectionChannelResponse>(&bd_addr(), move |evt| {
+                tx2.send(format!("failed {}", evt.conn_id)).unwrap()
+            });
+
+        // Sent directly rather than through connect, so the manager gets flicd's answers.
+        for conn_id in 1..=2 {
+            manager
+                .client
+                .send_command(commands::CreateConnectionChannel {
+                    conn_id,
+                    bd_addr: bd_addr(),
+                    latency_mode: LatencyMode::Normal,
+                    auto_disconnect_time: 511,
+                })
+                .unwrap();
+        }
+        let removed = Event::ConnectionChannelRemoved(ConnectionChannelRemoved {
+            conn_id: 2,
+            removed_reason: RemovedReason::ForceDisconnectedByOtherClient,
+        });
+        fake.send_event(&Event::CreateConnectionChannelResponse(
+            CreateConnectionChannelResponse {
+                conn_id: 1,
+                error: CreateConnectionChannelError::MaxPendingConnectionsReached,
+                connection_status: ConnectionStatus::Disconnected,
+            },
+        ))
+        .unwrap();
+        fake.send_event(&removed).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(String::from("failed 1")));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(String::from("removed 2")));
+
+        // Once handled, the channel is forgotten.
+        fake.send_event(&removed).unwrap();
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        let sub = manager.on::<PingResponse>(move |evt| tx.send(evt.ping_id).unwrap());
+        let _other = manager.on::<ButtonUpOrDown>(move |_| tx2.send(0).unwrap());
+
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 1 }))
+            .unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+
+        sub.unsubscribe();
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 2 }))
+            .unwrap();
+        // Wait for something the manager read after the ping, so we know the ping was skipped.
+        fake.send_event(&button_down(1)).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(0));
+    }
+
+    #[test]
+    fn once() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        let sub = manager.once::<PingResponse>(move |evt| tx.send(evt.ping_id).unwrap());
+        let _other = manager.on::<ButtonUpOrDown>(move |_| tx2.send(0).unwrap());
+        assert!(sub.is_active());
+
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 1 }))
+            .unwrap();
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 2 }))
+            .unwrap();
+        fake.send_event(&button_down(1)).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(0));
+        assert!(!sub.is_active());
+    }
+
+    #[test]
+    fn unsubscribe_from_handler() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        // The handler drops its own subscription the first time it's called.
+        let (tx, rx) = mpsc::channel();
+        let slot: Arc<Mutex<Option<Subscription>>> = Arc::new(Mutex::new(None));
+        let s = Arc::clone(&slot);
+        let sub = manager.register_handler(events::Opcode::PingResponse, move |_| {
+            tx.send(s.lock().unwrap().take().is_some()).unwrap();
+        });
+        *slot.lock().unwrap() = Some(sub);
+
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 1 }))
+            .unwrap();
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 2 }))
+            .unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(true));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn stop() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = Arc::new(Manager::new(&fake.addr()).unwrap());
+        let stop = manager.stop_handle();
+
+        let m = Arc::clone(&manager);
+        let handle = thread::spawn(move || m.start());
+        stop.stop();
+        handle.join().unwrap().unwrap();
+
+        // The connection is closed, and nothing was removed.
+        assert!(manager
+            .client
+            .send_command(commands::Ping { ping_id: 1 })
+            .is_err());
+        assert!(fake.commands().is_empty());
+    }
+
+    #[test]
+    fn stop_from_handler_and_clean_up() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = Arc::new(Manager::new(&fake.addr()).unwrap());
+        manager
+            .client
+            .send_command(commands::CreateConnectionChannel {
+                conn_id: 1,
+                bd_addr: bd_addr(),
+                latency_mode: LatencyMode::Normal,
+                auto_disconnect_time: 511,
+            })
+            .unwrap();
+        manager
+            .client
+            .send_command(commands::CreateScanner { scan_id: 2 })
+            .unwrap();
+        manager
+            .client
+            .send_command(commands::CreateBatteryStatusListener {
+                listener_id: 3,
+                bd_addr: bd_addr(),
+            })
+            .unwrap();
+        manager
+            .client
+            .send_command(commands::RemoveScanner { scan_id: 2 })
+            .unwrap();
+
+        let stop = manager.stop_handle();
+        let _sub = manager.once::<PingResponse>(move |_| stop.stop_and_clean_up());
+        let m = Arc::clone(&manager);
+        let handle = thread::spawn(move || m.start());
+
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 1 }))
+            .unwrap();
+        handle.join().unwrap().unwrap();
+
+        assert_eq!(
+            fake.wait_for_command(Opcode::RemoveBatteryStatusListener, TIMEOUT),
+            Some(AnyCommand::RemoveBatteryStatusListener(
+                commands::RemoveBatteryStatusListener { listener_id: 3 }
+            ))
+        );
+        let removed: Vec<AnyCommand> = fake.commands().into_iter().skip(4).collect();
+        assert_eq!(
+            removed,
+            vec![
+                AnyCommand::RemoveConnectionChannel(commands::RemoveConnectionChannel {
+                    conn_id: 1
+                }),
+                AnyCommand::RemoveBatteryStatusListener(commands::RemoveBatteryStatusListener {
+                    listener_id: 3
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_handler_on_worker_pool() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+        let manager = Arc::new(Manager::with_worker_pool(client, WorkerPool::default()));
+        let m = Arc::clone(&manager);
+        thread::spawn(move || m.start());
+
+        // The first ping blocks its handler until the button event has been handled.
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let (tx, rx) = mpsc::channel();
+        let tx2 = tx.clone();
+        let _pings = manager.on::<PingResponse>(move |evt| {
+            if evt.ping_id == 1 {
+                release_rx.lock().unwrap().recv().unwrap();
+            }
+            tx.send(format!("ping {}", evt.ping_id)).unwrap();
+        });
+        let _downs = manager.on::<ButtonUpOrDown>(move |evt| {
+            tx2.send(format!("down {}", evt.conn_id)).unwrap();
+            release_tx.send(()).unwrap();
+        });
+
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 1 }))
+            .unwrap();
+        fake.send_event(&button_down(5)).unwrap();
+
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(String::from("down 5")));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(String::from("ping 1")));
+    }
+
+    #[test]
+    fn full_handler_queue_is_reported() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+        let pool = WorkerPool {
+            workers: 1,
+            queue_size: 1,
+            overflow: Overflow::Error,
+        };
+        let manager = Arc::new(Manager::with_worker_pool(client, pool));
+        let m = Arc::clone(&manager);
+        thread::spawn(move || m.start());
+
+        let (err_tx, err_rx) = mpsc::channel();
+        let err_tx = Mutex::new(err_tx);
+        manager.on_error(move |err| err_tx.lock().unwrap().send(err.to_string()).unwrap());
+
+        // The first ping holds up the only worker until it's released.
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (started_tx, release_rx) = (Mutex::new(started_tx), Mutex::new(release_rx));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let _pings = manager.on::<PingResponse>(move |evt| {
+            if evt.ping_id == 1 {
+                started_tx.lock().unwrap().send(()).unwrap();
+                release_rx.lock().unwrap().recv().unwrap();
+            }
+            tx.lock().unwrap().send(evt.ping_id).unwrap();
+        });
+
+        let ping = |ping_id| {
+            fake.send_event(&Event::PingResponse(PingResponse { ping_id }))
+                .unwrap()
+        };
+        ping(1);
+        started_rx.recv_timeout(TIMEOUT).unwrap();
+        // 2 waits for the worker, and there's no room left for 3.
+        ping(2);
+        ping(3);
+        assert_eq!(
+            err_rx.recv_timeout(TIMEOUT),
+            Ok(FlicError::HandlerQueueFull.to_string())
+        );
+
+        release_tx.send(()).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(2));
+        ping(4);
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(4));
+    }
+
+    #[test]
+    fn register_from_handler() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = start(&fake);
+
+        // Handlers run without the handler lock held, so they can register more handlers.
+        let (tx, rx) = mpsc::channel();
+        let m = Arc::clone(&manager);
+        let _sub = manager.once::<ButtonUpOrDown>(move |_| {
+            m.on::<PingResponse>(move |evt| tx.send(evt.ping_id).unwrap())
+                .detach();
+        });
+
+        fake.send_event(&button_down(1)).unwrap();
+        fake.send_event(&Event::PingResponse(PingResponse { ping_id: 7 }))
+            .unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(7));
+    }
+}
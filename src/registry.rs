@@ -0,0 +1,340 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::enums::LatencyMode;
+use crate::error::FlicError;
+use crate::events::GetButtonInfoResponse;
+use crate::{BdAddr, Result, Uuid};
+
+/// Everything we remember about a button between runs.
+#[derive(Debug, PartialEq)]
+pub struct RegisteredButton {
+    pub bd_addr: BdAddr,
+    // The next three come from GetButtonInfoResponse, so they're unset until flicd has told us.
+    pub uuid: Option<Uuid>,
+    pub color: Option<String>,
+    pub serial_number: Option<String>,
+    pub nickname: Option<String>,
+    // The latency mode to create the button's connection channel with.
+    pub latency_mode: LatencyMode,
+    // The last battery percentage flicd reported, if it knew it.
+    pub battery_level: Option<u8>,
+}
+
+impl RegisteredButton {
+    pub fn new(bd_addr: BdAddr) -> RegisteredButton {
+        RegisteredButton {
+            bd_addr,
+            uuid: None,
+            color: None,
+            serial_number: None,
+            nickname: None,
+            latency_mode: LatencyMode::Normal,
+            battery_level: None,
+        }
+    }
+}
+
+/// The buttons known to the hub, stored in a file on disk. Changes are only written out by `save`.
+///
+/// The file has a section per button, headed by its address, e.g.
+///
+/// ```text
+/// [80:e4:da:71:12:34]
+/// uuid = 00010203-0405-0607-0809-0a0b0c0d0e0f
+/// color = white
+/// serial_number = AA00-A00000
+/// nickname = Kitchen
+/// latency_mode = normal
+/// battery_level = 87
+/// ```
+///
+/// Every key but the header is optional. Blank lines and lines starting with `#` are ignored.
+pub struct Registry {
+    path: PathBuf,
+    buttons: Vec<RegisteredButton>,
+}
+
+impl Registry {
+    /// Loads the registry stored at `path`, or starts an empty one if the file doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Registry> {
+        let path = path.as_ref().to_path_buf();
+        let buttons = match fs::read_to_string(&path) {
+            Ok(data) => parse(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(FlicError::from("failed to read registry", err)),
+        };
+        Ok(Registry { path, buttons })
+    }
+
+    /// Writes the registry back to the file it was opened from. The file is replaced atomically,
+    /// so a crash part way through leaves the old contents intact.
+    pub fn save(&self) -> Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        fs::write(&tmp, render(&self.buttons))
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .map_err(|err| FlicError::from("failed to write registry", err))
+    }
+
+    pub fn buttons(&self) -> &[RegisteredButton] {
+        &self.buttons
+    }
+
+    pub fn get(&self, bd_addr: &BdAddr) -> Option<&RegisteredButton> {
+        self.buttons.iter().find(|b| &b.bd_addr == bd_addr)
+    }
+
+    pub fn get_mut(&mut self, bd_addr: &BdAddr) -> Option<&mut RegisteredButton> {
+        self.buttons.iter_mut().find(|b| &b.bd_addr == bd_addr)
+    }
+
+    /// Returns the entry for `bd_addr`, adding a new one if the button isn't known yet.
+    pub fn add(&mut self, bd_addr: &BdAddr) -> &mut RegisteredButton {
+        match self.buttons.iter().position(|b| &b.bd_addr == bd_addr) {
+            Some(i) => &mut self.buttons[i],
+            None => {
+                self.buttons.push(RegisteredButton::new(*bd_addr));
+                self.buttons.last_mut().unwrap()
+            }
+        }
+    }
+
+    pub fn remove(&mut self, bd_addr: &BdAddr) -> Option<RegisteredButton> {
+        let i = self.buttons.iter().position(|b| &b.bd_addr == bd_addr)?;
+        Some(self.buttons.remove(i))
+    }
+
+    /// Records what flicd told us about a button, adding it if it isn't known yet.
+    pub fn update_button_info(&mut self, info: &GetButtonInfoResponse) {
+        let button = self.add(&info.bd_addr);
+        button.uuid = Some(info.uuid);
+        button.color = Some(info.color.clone());
+        button.serial_number = Some(info.serial_number.clone());
+    }
+
+    /// Records a battery percentage reported by a battery status listener, where -1 means flicd
+    /// doesn't know it.
+    pub fn update_battery_level(&mut self, bd_addr: &BdAddr, battery_percentage: i8) {
+        if let Some(button) = self.get_mut(bd_addr) {
+            button.battery_level = if battery_percentage < 0 {
+                None
+            } else {
+                Some(battery_percentage as u8)
+            };
+        }
+    }
+}
+
+fn parse(data: &str) -> Result<Vec<RegisteredButton>> {
+    let mut buttons: Vec<RegisteredButton> = vec![];
+
+    for (i, line) in data.lines().enumerate() {
+        let err = |msg: &str| FlicError::Generic(format!("registry line {}: {}", i + 1, msg));
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let bd_addr = line[1..line.len() - 1]
+                .parse()
+                .map_err(|_| err("invalid bluetooth address"))?;
+            if buttons.iter().any(|b| b.bd_addr == bd_addr) {
+                return Err(err("duplicate button"));
+            }
+            buttons.push(RegisteredButton::new(bd_addr));
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => return Err(err("expected a [button] header or key = value")),
+        };
+        let button = match buttons.last_mut() {
+            Some(button) => button,
+            None => return Err(err("key outside of a [button] section")),
+        };
+
+        match key {
+            "uuid" => button.uuid = Some(value.parse().map_err(|_| err("invalid uuid"))?),
+            "color" => button.color = Some(String::from(value)),
+            "serial_number" => button.serial_number = Some(String::from(value)),
+            "nickname" => button.nickname = Some(String::from(value)),
+            "latency_mode" => {
+                button.latency_mode = match value {
+                    "normal" => LatencyMode::Normal,
+                    "low" => LatencyMode::Low,
+                    "high" => LatencyMode::High,
+                    // Modes added after this was written are stored by number.
+                    _ => match value.parse::<u8>() {
+                        Ok(mode) => LatencyMode::from(mode),
+                        Err(_) => return Err(err("latency_mode must be normal, low or high")),
+                    },
+                }
+            }
+            "battery_level" => {
+                button.battery_level = match value.parse() {
+                    Ok(level) if level <= 100 => Some(level),
+                    _ => return Err(err("battery_level must be between 0 and 100")),
+                }
+            }
+            // Skip keys we don't know about, so files written by newer versions still load.
+            _ => {}
+        }
+    }
+
+    Ok(buttons)
+}
+
+fn render(buttons: &[RegisteredButton]) -> String {
+    let mut out = String::new();
+
+    for (i, button) in buttons.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let _ = writeln!(out, "[{}]", button.bd_addr);
+        if let Some(uuid) = &button.uuid {
+            let _ = writeln!(out, "uuid = {}", uuid);
+        }
+        if let Some(color) = &button.color {
+            let _ = writeln!(out, "color = {}", single_line(color));
+        }
+        if let Some(serial_number) = &button.serial_number {
+            let _ = writeln!(out, "serial_number = {}", single_line(serial_number));
+        }
+        if let Some(nickname) = &button.nickname {
+            let _ = writeln!(out, "nickname = {}", single_line(nickname));
+        }
+        let latency_mode = match button.latency_mode {
+            LatencyMode::Normal => String::from("normal"),
+            LatencyMode::Low => String::from("low"),
+            LatencyMode::High => String::from("high"),
+            LatencyMode::Unknown(mode) => mode.to_string(),
+        };
+        let _ = writeln!(out, "latency_mode = {}", latency_mode);
+        if let Some(battery_level) = button.battery_level {
+            let _ = writeln!(out, "battery_level = {}", battery_level);
+        }
+    }
+
+    out
+}
+
+// Values are one per line, so newlines in e.g. a nickname would corrupt the file.
+fn single_line(value: &str) -> String {
+    value.replace(['\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bd_addr, temp_path};
+
+    #[test]
+    fn missing_file_is_empty() {
+        let registry = Registry::open(temp_path()).unwrap();
+        assert!(registry.buttons().is_empty());
+    }
+
+    #[test]
+    fn save_and_open() {
+        let path = temp_path();
+        let mut registry = Registry::open(&path).unwrap();
+
+        registry.update_button_info(&GetButtonInfoResponse {
+            bd_addr: bd_addr(),
+            uuid: Uuid([
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
+                0x0E, 0x0F,
+            ]),
+            color: String::from("white"),
+            serial_number: String::from("AA00-A00000"),
+        });
+        let button = registry.add(&bd_addr());
+        button.nickname = Some(String::from("Kitchen\nsink"));
+        button.latency_mode = LatencyMode::Low;
+        registry.update_battery_level(&bd_addr(), 87);
+
+        let other = BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        registry.add(&other);
+        registry.update_battery_level(&other, -1);
+        registry.save().unwrap();
+
+        let data = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            data,
+            "[80:e4:da:71:12:34]\n\
+             uuid = 00010203-0405-0607-0809-0a0b0c0d0e0f\n\
+             color = white\n\
+             serial_number = AA00-A00000\n\
+             nickname = Kitchen sink\n\
+             latency_mode = low\n\
+             battery_level = 87\n\
+             \n\
+             [06:05:04:03:02:01]\n\
+             latency_mode = normal\n"
+        );
+
+        let mut loaded = Registry::open(&path).unwrap();
+        assert_eq!(loaded.buttons().len(), 2);
+        assert_eq!(
+            loaded.get(&bd_addr()).unwrap().nickname.as_deref(),
+            Some("Kitchen sink")
+        );
+        assert_eq!(loaded.get(&other), Some(&RegisteredButton::new(other)));
+
+        assert!(loaded.remove(&other).is_some());
+        assert!(loaded.get(&other).is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parse_errors() {
+        let tests = vec![
+            (
+                "nickname = x\n",
+                "registry line 1: key outside of a [button] section",
+            ),
+            ("[nope]\n", "registry line 1: invalid bluetooth address"),
+            (
+                "[80:e4:da:71:12:34]\n\n[80:e4:da:71:12:34]\n",
+                "registry line 3: duplicate button",
+            ),
+            (
+                "[80:e4:da:71:12:34]\nlatency_mode = fast\n",
+                "registry line 2: latency_mode must be normal, low or high",
+            ),
+            (
+                "[80:e4:da:71:12:34]\nbattery_level = 101\n",
+                "registry line 2: battery_level must be between 0 and 100",
+            ),
+            (
+                "[80:e4:da:71:12:34]\njunk\n",
+                "registry line 2: expected a [button] header or key = value",
+            ),
+        ];
+
+        for (data, want) in tests {
+            match parse(data) {
+                Err(err) => assert_eq!(err.to_string(), want),
+                Ok(_) => panic!("expected {:?} to fail", data),
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_comments_and_unknown_keys() {
+        let buttons =
+            parse("# buttons\n\n[80:e4:da:71:12:34]\n  nickname =  Desk \nfuture_key = 1\n")
+                .unwrap();
+        assert_eq!(buttons.len(), 1);
+        assert_eq!(buttons[0].nickname.as_deref(), Some("Desk"));
+    }
+}
@@ -0,0 +1,651 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use crate::enums::ClickType;
+use crate::error::FlicError;
+use crate::events::Event;
+use crate::{BdAddr, Result};
+
+// How long an HTTP action may take to connect, and then to send and get a response.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A click of a known button, which rules are matched against.
+#[derive(Debug, PartialEq)]
+pub struct Click {
+    pub bd_addr: BdAddr,
+    pub nickname: Option<String>,
+    pub click_type: ClickType,
+}
+
+impl Click {
+    /// Turns a button event into a click, using `button` to look up the address and nickname of
+    /// the button behind the event's connection channel.
+    ///
+    /// flicd reports most clicks through more than one event, so each click type is only taken
+    /// from one of them: `ButtonDown` and `ButtonUp` from `ButtonUpOrDown`, `ButtonClick` from
+    /// `ButtonClickOrHold`, and `ButtonSingleClick`, `ButtonDoubleClick` and `ButtonHold` from
+    /// `ButtonSingleOrDoubleClickOrHold`. That way an action runs once per click.
+    pub fn from_event<F>(evt: &Event, button: F) -> Option<Click>
+    where
+        F: FnOnce(u32) -> Option<(BdAddr, Option<String>)>,
+    {
+        let (conn_id, click_type) = match evt {
+            Event::ButtonUpOrDown(evt) => (evt.conn_id, evt.click_type),
+            Event::ButtonClickOrHold(evt) if evt.click_type == ClickType::ButtonClick => {
+                (evt.conn_id, evt.click_type)
+            }
+            Event::ButtonSingleOrDoubleClickOrHold(evt) => (evt.conn_id, evt.click_type),
+            _ => return None,
+        };
+
+        let (bd_addr, nickname) = button(conn_id)?;
+        Some(Click {
+            bd_addr,
+            nickname,
+            click_type,
+        })
+    }
+}
+
+/// Which button a rule applies to.
+#[derive(Debug, PartialEq)]
+pub enum ButtonSelector {
+    BdAddr(BdAddr),
+    // A nickname from the Registry.
+    Nickname(String),
+}
+
+impl ButtonSelector {
+    fn matches(&self, click: &Click) -> bool {
+        match self {
+            ButtonSelector::BdAddr(bd_addr) => &click.bd_addr == bd_addr,
+            ButtonSelector::Nickname(nickname) => click.nickname.as_ref() == Some(nickname),
+        }
+    }
+}
+
+/// Something to do when a button is clicked.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    // Runs the command with `sh -c`, without waiting for it to finish. The click is passed in the
+    // FLIC_BD_ADDR, FLIC_NICKNAME and FLIC_CLICK_TYPE environment variables.
+    Shell(String),
+
+    // Appends a line to the file, either the given text or the button's address and click type.
+    File {
+        path: String,
+        text: Option<String>,
+    },
+
+    // Sends an HTTP/1.1 request to a plain http:// URL, failing unless the response is a 2xx. The
+    // click is passed in the X-Flic-Bd-Addr and X-Flic-Click-Type headers.
+    Http {
+        method: String,
+        url: String,
+        body: Option<String>,
+    },
+}
+
+impl Action {
+    pub fn run(&self, click: &Click) -> Result<()> {
+        match self {
+            Action::Shell(command) => run_shell(command, click),
+            Action::File { path, text } => {
+                let line = match text {
+                    Some(text) => text.clone(),
+                    None => format!("{} {}", click.bd_addr, click_type_name(click.click_type)),
+                };
+                append_line(path, &line)
+                    .map_err(|err| FlicError::from(&format!("failed to write to {}", path), err))
+            }
+            Action::Http { method, url, body } => send_http(method, url, body.as_deref(), click)
+                .map_err(|err| FlicError::from(&format!("{} {} failed", method, url), err)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Rule {
+    pub button: ButtonSelector,
+    pub click_type: ClickType,
+    pub action: Action,
+}
+
+/// A set of rules mapping button clicks to actions, usually loaded from a file with a section per
+/// button, headed by its nickname or address, and a line per action, e.g.
+///
+/// ```text
+/// [Kitchen]
+/// single_click = shell notify-send "Kitchen button clicked"
+/// double_click = file /var/log/kitchen-clicks
+/// hold = http POST http://localhost:8123/lights/off
+///
+/// [80:e4:da:71:12:34]
+/// down = http http://localhost:8123/doorbell
+/// ```
+///
+/// The click types are `down`, `up`, `click`, `single_click`, `double_click` and `hold`, and a
+/// click type may have more than one action. Actions are written as:
+///
+/// - `shell COMMAND`
+/// - `file PATH [TEXT]`
+/// - `http [METHOD] URL [BODY]`, where the method defaults to POST.
+///
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Loads the rules stored at `path`, or no rules at all if the file doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Rules> {
+        match fs::read_to_string(path) {
+            Ok(data) => data.parse(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Rules::default()),
+            Err(err) => Err(FlicError::from("failed to read rules", err)),
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The actions to run for `click`, in the order they appear in the file.
+    pub fn matching<'a>(&'a self, click: &'a Click) -> impl Iterator<Item = &'a Action> + 'a {
+        self.rules
+            .iter()
+            .filter(move |r| r.click_type == click.click_type && r.button.matches(click))
+            .map(|r| &r.action)
+    }
+}
+
+impl FromStr for Rules {
+    type Err = FlicError;
+
+    fn from_str(data: &str) -> Result<Rules> {
+        let mut rules = vec![];
+        let mut button: Option<String> = None;
+
+        for (i, line) in data.lines().enumerate() {
+            let err = |msg: &str| FlicError::Generic(format!("rules line {}: {}", i + 1, msg));
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = line[1..line.len() - 1].trim();
+                if name.is_empty() {
+                    return Err(err("empty button name"));
+                }
+                button = Some(String::from(name));
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(err("expected a [button] header or click_type = action")),
+            };
+            let button = match &button {
+                Some(button) => button,
+                None => return Err(err("action outside of a [button] section")),
+            };
+
+            rules.push(Rule {
+                button: match button.parse() {
+                    Ok(bd_addr) => ButtonSelector::BdAddr(bd_addr),
+                    Err(_) => ButtonSelector::Nickname(button.clone()),
+                },
+                click_type: parse_click_type(key).ok_or_else(|| err("unknown click type"))?,
+                action: parse_action(value).map_err(|msg| err(&msg))?,
+            });
+        }
+
+        Ok(Rules { rules })
+    }
+}
+
+fn parse_click_type(name: &str) -> Option<ClickType> {
+    match name {
+        "down" => Some(ClickType::ButtonDown),
+        "up" => Some(ClickType::ButtonUp),
+        "click" => Some(ClickType::ButtonClick),
+        "single_click" => Some(ClickType::ButtonSingleClick),
+        "double_click" => Some(ClickType::ButtonDoubleClick),
+        "hold" => Some(ClickType::ButtonHold),
+        _ => None,
+    }
+}
+
+fn click_type_name(click_type: ClickType) -> &'static str {
+    match click_type {
+        ClickType::ButtonDown => "down",
+        ClickType::ButtonUp => "up",
+        ClickType::ButtonClick => "click",
+        ClickType::ButtonSingleClick => "single_click",
+        ClickType::ButtonDoubleClick => "double_click",
+        ClickType::ButtonHold => "hold",
+        ClickType::Unknown(_) => "unknown",
+    }
+}
+
+// Splits off the first whitespace-separated word, returning it and the (trimmed) rest.
+fn next_word(s: &str) -> (&str, Option<&str>) {
+    match s.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, Some(rest.trim()).filter(|r| !r.is_empty())),
+        None => (s, None),
+    }
+}
+
+fn parse_action(value: &str) -> std::result::Result<Action, String> {
+    let (kind, rest) = next_word(value);
+    match (kind, rest) {
+        ("shell", Some(command)) => Ok(Action::Shell(String::from(command))),
+        ("file", Some(rest)) => {
+            let (path, text) = next_word(rest);
+            Ok(Action::File {
+                path: String::from(path),
+                text: text.map(String::from),
+            })
+        }
+        ("http", Some(rest)) => {
+            let (first, after) = next_word(rest);
+            let (method, url, body) = if first.contains("://") {
+                ("POST", first, after)
+            } else {
+                match after.map(next_word) {
+                    Some((url, body)) => (first, url, body),
+                    None => return Err(String::from("http action needs a URL")),
+                }
+            };
+            split_url(url)?;
+            Ok(Action::Http {
+                method: String::from(method),
+                url: String::from(url),
+                body: body.map(String::from),
+            })
+        }
+        ("shell", None) | ("file", None) | ("http", None) => {
+            Err(format!("{} action needs an argument", kind))
+        }
+        _ => Err(format!("unknown action {:?}", kind)),
+    }
+}
+
+// Splits a URL into the host (with port, if any) and the path.
+fn split_url(url: &str) -> std::result::Result<(&str, &str), String> {
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None => return Err(format!("only http:// URLs are supported, got {:?}", url)),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("URL {:?} has no host", url));
+    }
+    Ok((host, path))
+}
+
+fn run_shell(command: &str, click: &Click) -> Result<()> {
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("FLIC_BD_ADDR", click.bd_addr.to_string())
+        .env("FLIC_CLICK_TYPE", click_type_name(click.click_type));
+    if let Some(nickname) = &click.nickname {
+        cmd.env("FLIC_NICKNAME", nickname);
+    }
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|err| FlicError::from(&format!("failed to run {:?}", command), err))?;
+    // Reap it in the background, so a slow command doesn't hold up other events.
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+fn append_line(path: &str, line: &str) -> io::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", line)
+}
+
+fn send_http(method: &str, url: &str, body: Option<&str>, click: &Click) -> io::Result<()> {
+    let (host, path) =
+        split_url(url).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let host_port = if host.contains(':') {
+        String::from(host)
+    } else {
+        format!("{}:80", host)
+    };
+    let addr = host_port
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let body = body.unwrap_or("");
+    let request = format!(
+        "{} {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: close\r\n\
+         X-Flic-Bd-Addr: {}\r\n\
+         X-Flic-Click-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {}",
+        method,
+        path,
+        host,
+        click.bd_addr,
+        click_type_name(click.click_type),
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    // We only care about the status, e.g. "HTTP/1.1 200 OK".
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    let status = status_line.split_whitespace().nth(1).unwrap_or("");
+    if status.starts_with('2') && status.len() == 3 {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "unexpected response {:?}",
+            status_line.trim_end()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{ButtonClickOrHold, ButtonSingleOrDoubleClick, ButtonUpOrDown};
+    use crate::testing::{bd_addr, temp_path};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn click(nickname: Option<&str>, click_type: ClickType) -> Click {
+        Click {
+            bd_addr: bd_addr(),
+            nickname: nickname.map(String::from),
+            click_type,
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules: Rules = "
+            # Lights
+            [Kitchen]
+            single_click = shell echo  hi
+            hold = http POST http://localhost:8123/lights/off {\"on\": false}
+
+            [80:e4:da:71:12:34]
+            down = http http://localhost:8123
+            double_click = file /tmp/clicks
+            double_click = file /tmp/state on
+        "
+        .parse()
+        .unwrap();
+
+        let kitchen = || ButtonSelector::Nickname(String::from("Kitchen"));
+        assert_eq!(
+            rules.rules(),
+            &[
+                Rule {
+                    button: kitchen(),
+                    click_type: ClickType::ButtonSingleClick,
+                    action: Action::Shell(String::from("echo  hi")),
+                },
+                Rule {
+                    button: kitchen(),
+                    click_type: ClickType::ButtonHold,
+                    action: Action::Http {
+                        method: String::from("POST"),
+                        url: String::from("http://localhost:8123/lights/off"),
+                        body: Some(String::from("{\"on\": false}")),
+                    },
+                },
+                Rule {
+                    button: ButtonSelector::BdAddr(bd_addr()),
+                    click_type: ClickType::ButtonDown,
+                    action: Action::Http {
+                        method: String::from("POST"),
+                        url: String::from("http://localhost:8123"),
+                        body: None,
+                    },
+                },
+                Rule {
+                    button: ButtonSelector::BdAddr(bd_addr()),
+                    click_type: ClickType::ButtonDoubleClick,
+                    action: Action::File {
+                        path: String::from("/tmp/clicks"),
+                        text: None,
+                    },
+                },
+                Rule {
+                    button: ButtonSelector::BdAddr(bd_addr()),
+                    click_type: ClickType::ButtonDoubleClick,
+                    action: Action::File {
+                        path: String::from("/tmp/state"),
+                        text: Some(String::from("on")),
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let tests = vec![
+            (
+                "hold = shell true",
+                "rules line 1: action outside of a [button] section",
+            ),
+            ("[]", "rules line 1: empty button name"),
+            (
+                "[a]\nhold",
+                "rules line 2: expected a [button] header or click_type = action",
+            ),
+            (
+                "[a]\ntriple_click = shell true",
+                "rules line 2: unknown click type",
+            ),
+            (
+                "[a]\nhold = shell",
+                "rules line 2: shell action needs an argument",
+            ),
+            (
+                "[a]\nhold = email me",
+                "rules line 2: unknown action \"email\"",
+            ),
+            (
+                "[a]\nhold = http GET",
+                "rules line 2: http action needs a URL",
+            ),
+            (
+                "[a]\nhold = http https://example.com",
+                "rules line 2: only http:// URLs are supported, got \"https://example.com\"",
+            ),
+        ];
+
+        for (data, want) in tests {
+            match data.parse::<Rules>() {
+                Err(err) => assert_eq!(err.to_string(), want),
+                Ok(_) => panic!("expected {:?} to fail", data),
+            }
+        }
+    }
+
+    #[test]
+    fn matching() {
+        let rules: Rules = "
+            [Kitchen]
+            hold = shell one
+            [80:e4:da:71:12:34]
+            hold = shell two
+            click = shell three
+        "
+        .parse()
+        .unwrap();
+
+        let names = |click: &Click| -> Vec<String> {
+            rules
+                .matching(click)
+                .map(|a| match a {
+                    Action::Shell(cmd) => cmd.clone(),
+                    _ => unreachable!(),
+                })
+                .collect()
+        };
+        assert_eq!(
+            names(&click(Some("Kitchen"), ClickType::ButtonHold)),
+            vec!["one", "two"]
+        );
+        assert_eq!(names(&click(None, ClickType::ButtonHold)), vec!["two"]);
+        assert!(names(&click(Some("Kitchen"), ClickType::ButtonDown)).is_empty());
+    }
+
+    #[test]
+    fn click_from_event() {
+        let lookup = |conn_id| {
+            if conn_id == 7 {
+                Some((bd_addr(), Some(String::from("Kitchen"))))
+            } else {
+                None
+            }
+        };
+
+        let evt = Event::ButtonUpOrDown(ButtonUpOrDown {
+            conn_id: 7,
+            click_type: ClickType::ButtonDown,
+            was_queued: false,
+            time_diff: 0,
+        });
+        assert_eq!(
+            Click::from_event(&evt, lookup),
+            Some(click(Some("Kitchen"), ClickType::ButtonDown))
+        );
+
+        // Holds come from ButtonSingleOrDoubleClickOrHold, and unknown channels are ignored.
+        let evt = Event::ButtonClickOrHold(ButtonClickOrHold {
+            conn_id: 7,
+            click_type: ClickType::ButtonHold,
+            was_queued: false,
+            time_diff: 0,
+        });
+        assert_eq!(Click::from_event(&evt, lookup), None);
+        let evt = Event::ButtonSingleOrDoubleClick(ButtonSingleOrDoubleClick {
+            conn_id: 7,
+            click_type: ClickType::ButtonSingleClick,
+            was_queued: false,
+            time_diff: 0,
+        });
+        assert_eq!(Click::from_event(&evt, lookup), None);
+        let evt = Event::ButtonUpOrDown(ButtonUpOrDown {
+            conn_id: 8,
+            click_type: ClickType::ButtonDown,
+            was_queued: false,
+            time_diff: 0,
+        });
+        assert_eq!(Click::from_event(&evt, lookup), None);
+    }
+
+    #[test]
+    fn file_and_shell_actions() {
+        let path = temp_path().to_string_lossy().into_owned();
+        let click = click(Some("Kitchen"), ClickType::ButtonHold);
+
+        Action::File {
+            path: path.clone(),
+            text: None,
+        }
+        .run(&click)
+        .unwrap();
+        Action::Shell(format!(
+            "echo \"$FLIC_NICKNAME $FLIC_CLICK_TYPE\" >> {}",
+            path
+        ))
+        .run(&click)
+        .unwrap();
+
+        // The shell command runs in the background.
+        let want = "80:e4:da:71:12:34 hold\nKitchen hold\n";
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(&path).unwrap() != want && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), want);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn http_action() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/lights", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for status in &["204 No Content", "500 Internal Server Error"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                // Every request has the same two byte body.
+                let mut request = vec![];
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\non") {
+                    let n = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            requests
+        });
+
+        let action = Action::Http {
+            method: String::from("PUT"),
+            url: url.clone(),
+            body: Some(String::from("on")),
+        };
+        let click = click(None, ClickType::ButtonSingleClick);
+        action.run(&click).unwrap();
+        let err = action.run(&click).unwrap_err();
+        assert!(
+            err.to_string().contains("500 Internal Server Error"),
+            "{}",
+            err
+        );
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests[0],
+            format!(
+                "PUT /lights HTTP/1.1\r\n\
+                 Host: {}\r\n\
+                 Connection: close\r\n\
+                 X-Flic-Bd-Addr: 80:e4:da:71:12:34\r\n\
+                 X-Flic-Click-Type: single_click\r\n\
+                 Content-Length: 2\r\n\
+                 \r\n\
+                 on",
+                &url[7..url.len() - 7]
+            )
+        );
+    }
+}
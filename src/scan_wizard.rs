@@ -0,0 +1,244 @@
+use crate::client::{Client, Subscription};
+use crate::commands;
+use crate::enums::ScanWizardResult;
+use crate::error::FlicError;
+use crate::events::Event;
+use crate::BdAddr;
+use crate::Result;
+
+/// A step the scan wizard has made towards pairing a new button.
+#[derive(Debug, PartialEq)]
+pub enum ScanWizardProgress {
+    /// A button was found, but it's private. Tell the user to hold it down for 7 seconds to make
+    /// it public.
+    FoundPrivateButton,
+
+    /// A public button was found, and the wizard is now connecting to it.
+    FoundPublicButton { bd_addr: BdAddr, name: String },
+
+    /// The button connected, and the wizard is now pairing with it and verifying it.
+    ButtonConnected,
+}
+
+/// A running scan wizard, created with `Client::scan_wizard`. It's cancelled if dropped before it
+/// completes.
+///
+/// Like requests, the wizard reads events from flicd itself if nobody else is, so its events
+/// never reach `next_event` or `Manager` handlers.
+pub struct ScanWizard<'a> {
+    client: &'a Client,
+    scan_wizard_id: u32,
+    sub: Subscription<'a>,
+    bd_addr: Option<BdAddr>,
+    completed: bool,
+}
+
+impl<'a> ScanWizard<'a> {
+    pub(crate) fn start(client: &'a Client, scan_wizard_id: u32) -> Result<ScanWizard<'a>> {
+        let sub = client.subscribe(
+            move |evt| match evt {
+                Event::ScanWizardFoundPrivateButton(evt) => evt.scan_wizard_id == scan_wizard_id,
+                Event::ScanWizardFoundPublicButton(evt) => evt.scan_wizard_id == scan_wizard_id,
+                Event::ScanWizardButtonConnected(evt) => evt.scan_wizard_id == scan_wizard_id,
+                Event::ScanWizardCompleted(evt) => evt.scan_wizard_id == scan_wizard_id,
+                _ => false,
+            },
+            false,
+        );
+
+        let mut wizard = ScanWizard {
+            client,
+            scan_wizard_id,
+            sub,
+            bd_addr: None,
+            completed: false,
+        };
+
+        if let Err(err) = client.send_command(commands::CreateScanWizard { scan_wizard_id }) {
+            // It never started, so there's nothing to cancel.
+            wizard.completed = true;
+            return Err(err);
+        }
+
+        Ok(wizard)
+    }
+
+    /// The id of the scan wizard, e.g. for sending a `CancelScanWizard` from another thread.
+    pub fn scan_wizard_id(&self) -> u32 {
+        self.scan_wizard_id
+    }
+
+    /// Asks flicd to cancel the wizard. `wait` will then fail with
+    /// `ScanWizardResult::WizardCancelledByUser`.
+    pub fn cancel(&self) -> Result<()> {
+        self.client.send_command(commands::CancelScanWizard {
+            scan_wizard_id: self.scan_wizard_id,
+        })
+    }
+
+    /// Blocks until the wizard completes, calling `progress` for every step along the way.
+    /// Returns the address of the newly paired button, or `FlicError::ScanWizard` with the reason
+    /// the wizard failed.
+    pub fn wait<F>(&mut self, mut progress: F) -> Result<BdAddr>
+    where
+        F: FnMut(ScanWizardProgress),
+    {
+        loop {
+            let evt = self
+                .sub
+                .recv(None)?
+                .expect("call is blocking, event must be returned");
+
+            match evt {
+                Event::ScanWizardFoundPrivateButton(_) => {
+                    progress(ScanWizardProgress::FoundPrivateButton)
+                }
+                Event::ScanWizardFoundPublicButton(evt) => {
+                    self.bd_addr = Some(evt.bd_addr);
+                    progress(ScanWizardProgress::FoundPublicButton {
+                        bd_addr: evt.bd_addr,
+                        name: evt.name,
+                    });
+                }
+                Event::ScanWizardButtonConnected(_) => {
+                    progress(ScanWizardProgress::ButtonConnected)
+                }
+                Event::ScanWizardCompleted(evt) => {
+                    self.completed = true;
+                    return match (evt.result, self.bd_addr.take()) {
+                        (ScanWizardResult::WizardSuccess, Some(bd_addr)) => Ok(bd_addr),
+                        (ScanWizardResult::WizardSuccess, None) => Err(FlicError::Generic(
+                            String::from("scan wizard succeeded without finding a button"),
+                        )),
+                        (result, _) => Err(FlicError::ScanWizard(result)),
+                    };
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+impl<'a> Drop for ScanWizard<'a> {
+    fn drop(&mut self) {
+        if !self.completed {
+            let _ = self.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{AnyCommand, Opcode};
+    use crate::events::{
+        ScanWizardButtonConnected, ScanWizardCompleted, ScanWizardFoundPrivateButton,
+        ScanWizardFoundPublicButton,
+    };
+    use crate::testing::{bd_addr, FakeFlicd, TIMEOUT};
+    use std::time::Duration;
+
+    // Makes the fake run through the whole wizard as soon as it's created, finishing with result.
+    fn run_wizard(fake: &FakeFlicd, result: ScanWizardResult) {
+        fake.respond_to(Opcode::CreateScanWizard, move |cmd| {
+            let scan_wizard_id = match cmd {
+                AnyCommand::CreateScanWizard(cmd) => cmd.scan_wizard_id,
+                _ => return vec![],
+            };
+            vec![
+                Event::ScanWizardFoundPrivateButton(ScanWizardFoundPrivateButton {
+                    scan_wizard_id,
+                }),
+                Event::ScanWizardFoundPublicButton(ScanWizardFoundPublicButton {
+                    scan_wizard_id,
+                    bd_addr: bd_addr(),
+                    name: String::from("F022Ph"),
+                }),
+                Event::ScanWizardButtonConnected(ScanWizardButtonConnected { scan_wizard_id }),
+                Event::ScanWizardCompleted(ScanWizardCompleted {
+                    scan_wizard_id,
+                    result,
+                }),
+            ]
+        });
+    }
+
+    #[test]
+    fn pairs_button() {
+        let fake = FakeFlicd::start().unwrap();
+        run_wizard(&fake, ScanWizardResult::WizardSuccess);
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let mut steps = vec![];
+        let got = client.pair(|step| steps.push(step)).unwrap();
+
+        assert_eq!(got, bd_addr());
+        assert_eq!(
+            steps,
+            vec![
+                ScanWizardProgress::FoundPrivateButton,
+                ScanWizardProgress::FoundPublicButton {
+                    bd_addr: bd_addr(),
+                    name: String::from("F022Ph"),
+                },
+                ScanWizardProgress::ButtonConnected,
+            ]
+        );
+
+        // It completed, so there was nothing to cancel.
+        assert!(fake
+            .wait_for_command(Opcode::CancelScanWizard, Duration::from_millis(100))
+            .is_none());
+    }
+
+    #[test]
+    fn maps_failures() {
+        let fake = FakeFlicd::start().unwrap();
+        run_wizard(&fake, ScanWizardResult::WizardInternetBackendError);
+        let client = Client::new(&fake.addr()).unwrap();
+
+        match client.pair(|_| {}) {
+            Err(FlicError::ScanWizard(ScanWizardResult::WizardInternetBackendError)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(Opcode::CancelScanWizard, |cmd| match cmd {
+            AnyCommand::CancelScanWizard(cmd) => {
+                vec![Event::ScanWizardCompleted(ScanWizardCompleted {
+                    scan_wizard_id: cmd.scan_wizard_id,
+                    result: ScanWizardResult::WizardCancelledByUser,
+                })]
+            }
+            _ => vec![],
+        });
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let mut wizard = client.scan_wizard().unwrap();
+        wizard.cancel().unwrap();
+        match wizard.wait(|_| {}) {
+            Err(FlicError::ScanWizard(ScanWizardResult::WizardCancelledByUser)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cancel_on_drop() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        let wizard = client.scan_wizard().unwrap();
+        let scan_wizard_id = wizard.scan_wizard_id();
+        drop(wizard);
+
+        assert_eq!(
+            fake.wait_for_command(Opcode::CancelScanWizard, TIMEOUT),
+            Some(AnyCommand::CancelScanWizard(commands::CancelScanWizard {
+                scan_wizard_id
+            }))
+        );
+    }
+}
@@ -0,0 +1,451 @@
+//! An in-process stand-in for flicd, for exercising `Client` and `Manager` without Bluetooth
+//! hardware.
+//!
+//! `FakeFlicd` listens on a local TCP port and speaks the same framing as the real service: every
+//! packet is a little endian u16 length followed by an opcode byte and the body. Commands sent by
+//! the client are recorded, and tests can script responses to them or push events at any time.
+//!
+//! ```no_run
+//! use flic::commands::{AnyCommand, Opcode};
+//! use flic::events::{Event, PingResponse};
+//! use flic::testing::FakeFlicd;
+//!
+//! let fake = FakeFlicd::start().unwrap();
+//! fake.respond_to(Opcode::Ping, |cmd| match cmd {
+//!     AnyCommand::Ping(ping) => vec![Event::PingResponse(PingResponse {
+//!         ping_id: ping.ping_id,
+//!     })],
+//!     _ => vec![],
+//! });
+//! let client = flic::Client::new(&fake.addr()).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::commands::{self, AnyCommand};
+use crate::enums::ClickType;
+use crate::events::{self, ButtonUpOrDown, Event};
+use crate::BdAddr;
+
+// How long send_event waits for a client to connect before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Responder = Arc<dyn Fn(&AnyCommand) -> Vec<Event> + Send + Sync + 'static>;
+
+pub struct FakeFlicd {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    accept_handle: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cond: Condvar,
+    shutdown: AtomicBool,
+}
+
+#[derive(Default)]
+struct State {
+    // Write half of the most recently accepted connection.
+    conn: Option<TcpStream>,
+    // Every command frame received so far, in order, as opcode followed by body.
+    commands: Vec<Vec<u8>>,
+    responders: HashMap<commands::Opcode, Responder>,
+}
+
+impl FakeFlicd {
+    /// Binds an ephemeral port on localhost and starts accepting clients in the background.
+    pub fn start() -> io::Result<FakeFlicd> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let s = Arc::clone(&shared);
+        let accept_handle = thread::spawn(move || accept_loop(listener, s));
+
+        Ok(FakeFlicd {
+            addr,
+            shared,
+            accept_handle: Some(accept_handle),
+        })
+    }
+
+    /// The host:port address to hand to `Client::new`.
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Registers a function to be called whenever a command with the given opcode arrives. The
+    /// function returns the events to send back, in order. Registering a second responder for the
+    /// same opcode replaces the first.
+    pub fn respond_to<F>(&self, opcode: commands::Opcode, f: F)
+    where
+        F: Fn(&AnyCommand) -> Vec<Event> + Send + Sync + 'static,
+    {
+        let mut state = self.shared.state.lock().unwrap();
+        state.responders.insert(opcode, Arc::new(f));
+    }
+
+    /// Sends an event to the connected client, waiting for one to connect if necessary.
+    pub fn send_event(&self, evt: &Event) -> io::Result<()> {
+        self.send_frame(&events::marshal(evt))
+    }
+
+    /// Sends a raw frame (opcode followed by body) to the connected client, waiting for one to
+    /// connect if necessary. Useful for sending malformed events.
+    pub fn send_frame(&self, frame: &[u8]) -> io::Result<()> {
+        let len = (frame.len() as u16).to_le_bytes();
+        let mut buf = Vec::with_capacity(frame.len() + 2);
+        buf.extend_from_slice(&len);
+        buf.extend_from_slice(frame);
+        self.send_bytes(&buf)
+    }
+
+    /// Sends bytes to the connected client exactly as given, without a length header, waiting for
+    /// one to connect if necessary. Useful for splitting a packet across several writes.
+    pub fn send_bytes(&self, data: &[u8]) -> io::Result<()> {
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(conn) = state.conn.as_mut() {
+                conn.write_all(data)?;
+                return conn.flush();
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    ErrorKind::NotConnected,
+                    "no client connected to fake flicd",
+                ));
+            }
+            state = self
+                .shared
+                .cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Returns every command received so far, in order.
+    pub fn commands(&self) -> Vec<AnyCommand> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .commands
+            .iter()
+            .filter_map(|frame| commands::unmarshal(frame).ok())
+            .map(|(cmd, _)| cmd)
+            .collect()
+    }
+
+    /// Blocks until a command with the given opcode has been received and returns it, or `None`
+    /// if none arrives within the timeout. Commands that were already received count.
+    pub fn wait_for_command(
+        &self,
+        opcode: commands::Opcode,
+        timeout: Duration,
+    ) -> Option<AnyCommand> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            let found = state
+                .commands
+                .iter()
+                .filter_map(|frame| commands::unmarshal(frame).ok())
+                .find(|(_, op)| *op == opcode);
+            if let Some((cmd, _)) = found {
+                return Some(cmd);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self
+                .shared
+                .cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Closes the current client connection, as if flicd had gone away. New clients can still
+    /// connect afterwards.
+    pub fn disconnect(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(conn) = state.conn.take() {
+            let _ = conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for FakeFlicd {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        self.disconnect();
+
+        // Wake up the accept loop so it notices we're shutting down.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.accept_handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A writer that keeps everything written to it in memory. Clones share the same buffer, so a test
+/// can hand one to a `Client` or `CaptureWriter` and look at what was written through another.
+#[derive(Clone, Default)]
+pub struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    /// Returns a copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How long tests wait for something that should happen promptly before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The button address tests use when any one will do, 80:e4:da:71:12:34.
+pub fn bd_addr() -> BdAddr {
+    BdAddr::from_bytes([0x34, 0x12, 0x71, 0xda, 0xe4, 0x80])
+}
+
+/// A `ButtonDown` event on the given connection channel.
+pub fn button_down(conn_id: u32) -> Event {
+    Event::ButtonUpOrDown(ButtonUpOrDown {
+        conn_id,
+        click_type: ClickType::ButtonDown,
+        was_queued: false,
+        time_diff: 0,
+    })
+}
+
+/// A path in the temp dir that no other test in this process is using. Nothing is created there.
+pub fn temp_path() -> PathBuf {
+    static COUNT: AtomicU32 = AtomicU32::new(0);
+    std::env::temp_dir().join(format!(
+        "flic-test-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ))
+}
+
+fn accept_loop(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        if shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        let writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(_) => continue,
+        };
+
+        {
+            let mut state = shared.state.lock().unwrap();
+            if let Some(old) = state.conn.replace(writer) {
+                let _ = old.shutdown(Shutdown::Both);
+            }
+        }
+        shared.cond.notify_all();
+
+        let s = Arc::clone(&shared);
+        thread::spawn(move || serve(stream, s));
+    }
+}
+
+fn serve(mut stream: TcpStream, shared: Arc<Shared>) {
+    loop {
+        let mut header = [0u8; 2];
+        if stream.read_exact(&mut header).is_err() {
+            return;
+        }
+
+        let len = u16::from_le_bytes(header) as usize;
+        let mut frame = vec![0u8; len];
+        if stream.read_exact(&mut frame).is_err() {
+            return;
+        }
+
+        {
+            let mut state = shared.state.lock().unwrap();
+            state.commands.push(frame.clone());
+        }
+        shared.cond.notify_all();
+
+        let (cmd, opcode) = match commands::unmarshal(&frame) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+
+        let responder = shared
+            .state
+            .lock()
+            .unwrap()
+            .responders
+            .get(&opcode)
+            .cloned();
+        if let Some(responder) = responder {
+            let evts = responder(&cmd);
+
+            // Write through the shared connection so responses don't interleave with events sent
+            // by send_event.
+            let mut state = shared.state.lock().unwrap();
+            let conn = match state.conn.as_mut() {
+                Some(conn) => conn,
+                None => return,
+            };
+            for evt in evts {
+                if write_frame(conn, &events::marshal(&evt)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    let len = (frame.len() as u16).to_le_bytes();
+    let mut buf = Vec::with_capacity(frame.len() + 2);
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(frame);
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{CreateScanner, GetInfo};
+    use crate::enums::{BdAddrType, BluetoothControllerState};
+    use crate::events::{GetInfoResponse, Opcode};
+    use crate::{BdAddr, Client, Manager};
+    use std::sync::mpsc;
+
+    #[test]
+    fn responds_to_get_info() {
+        let fake = FakeFlicd::start().unwrap();
+        fake.respond_to(commands::Opcode::GetInfo, |_| {
+            vec![Event::GetInfoResponse(GetInfoResponse {
+                bluetooth_controller_state: BluetoothControllerState::Attached,
+                my_bd_addr: BdAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                my_bd_addr_type: BdAddrType::PublicBdAddrType,
+                max_pending_connections: 15,
+                max_concurrently_connected_buttons: -1,
+                current_pending_connections: 0,
+                currently_no_space_for_new_connection: false,
+                nb_verified_buttons: 0,
+                bd_addr_of_verified_buttons: vec![],
+            })]
+        });
+
+        let client = Client::new(&fake.addr()).unwrap();
+        client.send_command(GetInfo {}).unwrap();
+
+        let (evt, opcode) = client
+            .next_event_with_timeout(Some(TIMEOUT))
+            .unwrap()
+            .expect("timed out waiting for GetInfoResponse");
+        assert_eq!(opcode, Opcode::GetInfoResponse);
+        match evt {
+            Event::GetInfoResponse(info) => {
+                assert_eq!(
+                    info.bluetooth_controller_state,
+                    BluetoothControllerState::Attached
+                );
+                assert_eq!(info.max_concurrently_connected_buttons, -1);
+            }
+            _ => panic!("unexpected event {:?}", evt),
+        }
+    }
+
+    #[test]
+    fn records_commands() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+        client
+            .send_command(CreateScanner {
+                scan_id: 0x12345678,
+            })
+            .unwrap();
+
+        assert_eq!(
+            fake.wait_for_command(commands::Opcode::CreateScanner, TIMEOUT),
+            Some(AnyCommand::CreateScanner(CreateScanner {
+                scan_id: 0x12345678
+            }))
+        );
+        assert_eq!(fake.commands().len(), 1);
+    }
+
+    #[test]
+    fn manager_receives_button_events() {
+        let fake = FakeFlicd::start().unwrap();
+        let manager = Arc::new(Manager::new(&fake.addr()).unwrap());
+
+        let (tx, rx) = mpsc::channel();
+        manager
+            .register_handler(Opcode::ButtonUpOrDown, move |evt| {
+                if let Event::ButtonUpOrDown(evt) = evt {
+                    tx.send(evt.conn_id).unwrap();
+                }
+            })
+            .detach();
+
+        let m = Arc::clone(&manager);
+        thread::spawn(move || m.start());
+
+        fake.send_event(&button_down(42)).unwrap();
+
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(42));
+    }
+
+    #[test]
+    fn disconnect_surfaces_as_flicd_error() {
+        let fake = FakeFlicd::start().unwrap();
+        let client = Client::new(&fake.addr()).unwrap();
+
+        // Make sure the fake has accepted us before hanging up.
+        fake.send_event(&button_down(1)).unwrap();
+        let (evt, _) = client.next_event().unwrap();
+        assert_eq!(evt, button_down(1));
+
+        fake.disconnect();
+        match client.next_event() {
+            Err(crate::FlicError::FlicD(_)) => {}
+            other => panic!("expected FlicD error, got {:?}", other),
+        }
+    }
+}
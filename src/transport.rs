@@ -0,0 +1,265 @@
+// The connection to flicd. Client::new connects over a TCP socket or a Unix domain socket:
+// addresses starting with "unix:" name a socket path, e.g. "unix:/run/flicd.sock", and anything else
+// is a host:port. Client::from_parts takes any Read and Write instead.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+// Prefix of addresses that are a Unix socket path rather than a host:port.
+const UNIX_PREFIX: &str = "unix:";
+
+// How much ThreadedReader reads at a time.
+const READ_CHUNK: usize = 512;
+
+// The half of a connection a client reads events from. Once the read timeout passes, reads fail
+// with WouldBlock or TimedOut.
+pub(crate) trait ReadHalf: Read + Send {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+// The half of a connection a client sends commands on.
+pub(crate) trait WriteHalf: Write + Send {
+    // Closes the connection, so that the reading half reaches the end of the stream.
+    fn shutdown(&mut self) -> io::Result<()>;
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn connect(addr: &str) -> io::Result<Stream> {
+        match addr.strip_prefix(UNIX_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets aren't supported on this platform",
+            )),
+            None => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => Ok(Stream::Tcp(s.try_clone()?)),
+            #[cfg(unix)]
+            Stream::Unix(s) => Ok(Stream::Unix(s.try_clone()?)),
+        }
+    }
+}
+
+impl ReadHalf for Stream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl WriteHalf for Stream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+// Everything read from a plain Read, in the order it was read. An empty chunk means the end of the
+// stream.
+type Chunk = io::Result<Vec<u8>>;
+
+// Splits an arbitrary reader and writer into the two halves of a connection. The reader is read on
+// a thread of its own, which is what makes read timeouts possible.
+pub(crate) fn split<R, W>(reader: R, writer: W) -> (ThreadedReader, PlainWriter<W>)
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let closer = tx.clone();
+    thread::spawn(move || read_chunks(reader, tx));
+
+    (
+        ThreadedReader {
+            rx,
+            pending: Vec::new(),
+            timeout: None,
+            done: false,
+        },
+        PlainWriter {
+            inner: writer,
+            closer,
+        },
+    )
+}
+
+fn read_chunks<R: Read>(mut reader: R, tx: Sender<Chunk>) {
+    let mut buf = [0u8; READ_CHUNK];
+    loop {
+        let chunk = match reader.read(&mut buf) {
+            Ok(n) => Ok(buf[..n].to_vec()),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+
+        let last = !matches!(chunk, Ok(ref data) if !data.is_empty());
+        // Stop once nobody is listening, or there's nothing more to read.
+        if tx.send(chunk).is_err() || last {
+            return;
+        }
+    }
+}
+
+pub(crate) struct ThreadedReader {
+    rx: Receiver<Chunk>,
+    // What's left of the last chunk, if it didn't fit in the caller's buffer.
+    pending: Vec<u8>,
+    timeout: Option<Duration>,
+    // Set once we've reached the end of the stream.
+    done: bool,
+}
+
+impl ReadHalf for ThreadedReader {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Read for ThreadedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.done {
+            let chunk = match self.timeout {
+                Some(timeout) => match self.rx.recv_timeout(timeout) {
+                    Ok(chunk) => Some(chunk),
+                    Err(RecvTimeoutError::Timeout) => return Err(ErrorKind::WouldBlock.into()),
+                    Err(RecvTimeoutError::Disconnected) => None,
+                },
+                None => self.rx.recv().ok(),
+            };
+
+            match chunk {
+                Some(Ok(data)) if !data.is_empty() => self.pending = data,
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Err(err);
+                }
+                _ => self.done = true,
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+pub(crate) struct PlainWriter<W> {
+    inner: W,
+    // Ends the stream for the ThreadedReader it was split with, so closing the client wakes up
+    // anyone waiting on an event.
+    closer: Sender<Chunk>,
+}
+
+impl<W: Write + Send> WriteHalf for PlainWriter<W> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        let _ = self.closer.send(Ok(Vec::new()));
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for PlainWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::commands::{AnyCommand, Ping};
+    use crate::events::{self, Event, PingResponse};
+    use crate::framing::FrameReader;
+    use crate::Client;
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+    use std::{env, fs, process, thread};
+
+    #[test]
+    fn client_over_unix_socket() {
+        let path = env::temp_dir().join(format!("flic-transport-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Answers a single ping, like flicd would.
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = FrameReader::new(conn.try_clone().unwrap());
+            let frame = reader.read_frame().unwrap().unwrap();
+            let ping_id = match crate::commands::unmarshal(&frame).unwrap().0 {
+                AnyCommand::Ping(ping) => ping.ping_id,
+                cmd => panic!("unexpected command {:?}", cmd),
+            };
+
+            let evt = events::marshal(&Event::PingResponse(PingResponse { ping_id }));
+            let mut packet = (evt.len() as u16).to_le_bytes().to_vec();
+            packet.extend_from_slice(&evt);
+            conn.write_all(&packet).unwrap();
+        });
+
+        let client = Client::new(&format!("unix:{}", path.display())).unwrap();
+        client.send_command(Ping { ping_id: 42 }).unwrap();
+        assert_eq!(
+            client.next_event().unwrap().0,
+            Event::PingResponse(PingResponse { ping_id: 42 })
+        );
+
+        server.join().unwrap();
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::error::FlicError;
+use crate::Result;
+
+/// Runs `Manager` handlers on a fixed set of threads instead of the thread reading events, see
+/// `Manager::with_worker_pool`. Handlers can then run at the same time as each other, and calls
+/// for later events can finish before calls for earlier ones.
+#[derive(Clone, Debug)]
+pub struct WorkerPool {
+    pub workers: usize,
+    // How many handler calls can be waiting for a worker before overflow kicks in.
+    pub queue_size: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for WorkerPool {
+    fn default() -> WorkerPool {
+        WorkerPool {
+            workers: 4,
+            queue_size: 64,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+/// What to do with a handler call when the worker pool's queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // Drop the oldest waiting call to make room.
+    DropOldest,
+
+    // Wait for a worker to make room. This holds up reading events, like running handlers inline.
+    Block,
+
+    // Drop the new call, and report FlicError::HandlerQueueFull to the function registered with
+    // Manager::on_error.
+    Error,
+}
+
+pub(crate) type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub(crate) struct Pool {
+    shared: Arc<Shared>,
+    queue_size: usize,
+    overflow: Overflow,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    // Set when the pool is dropped. Workers finish what's queued and then exit.
+    shutdown: bool,
+}
+
+impl Pool {
+    pub(crate) fn start(config: &WorkerPool) -> Pool {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                shutdown: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        });
+
+        for _ in 0..config.workers.max(1) {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || work(&shared));
+        }
+
+        Pool {
+            shared,
+            queue_size: config.queue_size.max(1),
+            overflow: config.overflow,
+        }
+    }
+
+    pub(crate) fn submit(&self, job: Job) -> Result<()> {
+        let mut queue = self.shared.queue.lock().unwrap();
+
+        while queue.jobs.len() >= self.queue_size {
+            match self.overflow {
+                Overflow::DropOldest => {
+                    queue.jobs.pop_front();
+                }
+                Overflow::Block => queue = self.shared.not_full.wait(queue).unwrap(),
+                Overflow::Error => return Err(FlicError::HandlerQueueFull),
+            }
+        }
+
+        queue.jobs.push_back(job);
+        self.shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if let Some(job) = queue.jobs.pop_front() {
+                    shared.not_full.notify_one();
+                    break job;
+                }
+                if queue.shutdown {
+                    return;
+                }
+                queue = shared.not_empty.wait(queue).unwrap();
+            }
+        };
+
+        // A panicking handler shouldn't take a worker down with it. The panic is still reported
+        // by the panic hook.
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TIMEOUT;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    // Starts a pool with a single worker and room for one waiting job, and occupies the worker
+    // until the returned sender is used.
+    fn busy_pool(overflow: Overflow) -> (Pool, mpsc::Sender<()>) {
+        let pool = Pool::start(&WorkerPool {
+            workers: 1,
+            queue_size: 1,
+            overflow,
+        });
+
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        pool.submit(Box::new(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        }))
+        .unwrap();
+        started_rx.recv_timeout(TIMEOUT).unwrap();
+
+        (pool, release_tx)
+    }
+
+    fn send_job(tx: &mpsc::Sender<u32>, n: u32) -> Job {
+        let tx = tx.clone();
+        Box::new(move || tx.send(n).unwrap())
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let (pool, release) = busy_pool(Overflow::DropOldest);
+        let (tx, rx) = mpsc::channel();
+
+        pool.submit(send_job(&tx, 1)).unwrap();
+        pool.submit(send_job(&tx, 2)).unwrap();
+        release.send(()).unwrap();
+
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(2));
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn error() {
+        let (pool, release) = busy_pool(Overflow::Error);
+        let (tx, rx) = mpsc::channel();
+
+        pool.submit(send_job(&tx, 1)).unwrap();
+        match pool.submit(send_job(&tx, 2)) {
+            Err(FlicError::HandlerQueueFull) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        release.send(()).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+
+        // Only the call that didn't fit is lost.
+        pool.submit(send_job(&tx, 3)).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(3));
+    }
+
+    #[test]
+    fn block() {
+        let (pool, release) = busy_pool(Overflow::Block);
+        let (tx, rx) = mpsc::channel();
+
+        pool.submit(send_job(&tx, 1)).unwrap();
+        let submitter = thread::spawn(move || {
+            pool.submit(send_job(&tx, 2)).unwrap();
+            pool
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!submitter.is_finished());
+
+        release.send(()).unwrap();
+        let _pool = submitter.join().unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(2));
+    }
+
+    #[test]
+    fn survives_panics() {
+        let pool = Pool::start(&WorkerPool {
+            workers: 1,
+            ..WorkerPool::default()
+        });
+        let (tx, rx) = mpsc::channel();
+
+        pool.submit(Box::new(|| panic!("handler failed"))).unwrap();
+        pool.submit(send_job(&tx, 1)).unwrap();
+        assert_eq!(rx.recv_timeout(TIMEOUT), Ok(1));
+    }
+}
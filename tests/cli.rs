@@ -0,0 +1,273 @@
+// Runs the cli binary against a FakeFlicd, checking what it sends and what it prints.
+
+use flic::commands::{AnyCommand, Opcode};
+use flic::enums::{
+    BdAddrType, BluetoothControllerState, ConnectionStatus, CreateConnectionChannelError,
+    RemovedReason, ScanWizardResult,
+};
+use flic::events::{
+    BatteryStatus, ButtonDeleted, ConnectionChannelRemoved, CreateConnectionChannelResponse, Event,
+    GetButtonInfoResponse, GetInfoResponse, ScanWizardButtonConnected, ScanWizardCompleted,
+    ScanWizardFoundPublicButton,
+};
+use flic::testing::{bd_addr, button_down, FakeFlicd, TIMEOUT};
+use flic::{BdAddr, Uuid};
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Output, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+fn cli(fake: &FakeFlicd) -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_cli"));
+    cmd.arg("--flicd_addr").arg(fake.addr());
+    cmd
+}
+
+fn run(fake: &FakeFlicd, args: &[&str]) -> Output {
+    cli(fake).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(
+        output.status.success(),
+        "cli failed with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+fn respond_with_info(fake: &FakeFlicd, buttons: Vec<BdAddr>) {
+    fake.respond_to(Opcode::GetInfo, move |_| {
+        vec![Event::GetInfoResponse(GetInfoResponse {
+            bluetooth_controller_state: BluetoothControllerState::Attached,
+            my_bd_addr: BdAddr::from_bytes([0x06, 0x05, 0x04, 0x03, 0x02, 0x01]),
+            my_bd_addr_type: BdAddrType::PublicBdAddrType,
+            max_pending_connections: 15,
+            max_concurrently_connected_buttons: -1,
+            current_pending_connections: 0,
+            currently_no_space_for_new_connection: false,
+            nb_verified_buttons: buttons.len() as u16,
+            bd_addr_of_verified_buttons: buttons.clone(),
+        })]
+    });
+}
+
+// What flicd says when it creates a channel to a button that's already connected.
+fn channel_created(conn_id: u32) -> Event {
+    Event::CreateConnectionChannelResponse(CreateConnectionChannelResponse {
+        conn_id,
+        error: CreateConnectionChannelError::NoError,
+        connection_status: ConnectionStatus::Ready,
+    })
+}
+
+#[test]
+fn info_describes_controller() {
+    let fake = FakeFlicd::start().unwrap();
+    respond_with_info(&fake, vec![bd_addr()]);
+
+    let out = stdout(&run(&fake, &["info"]));
+
+    assert!(out.contains("Address:"), "{}", out);
+    assert!(out.contains("01:02:03:04:05:06"), "{}", out);
+    assert!(out.contains("Verified buttons:           1"), "{}", out);
+}
+
+#[test]
+fn buttons_lists_verified_buttons() {
+    let fake = FakeFlicd::start().unwrap();
+    respond_with_info(&fake, vec![bd_addr()]);
+    fake.respond_to(Opcode::GetButtonInfo, |cmd| match cmd {
+        AnyCommand::GetButtonInfo(cmd) => {
+            vec![Event::GetButtonInfoResponse(GetButtonInfoResponse {
+                bd_addr: cmd.bd_addr,
+                uuid: Uuid::from_bytes([0; 16]),
+                color: String::from("white"),
+                serial_number: String::new(),
+            })]
+        }
+        _ => vec![],
+    });
+
+    let out = stdout(&run(&fake, &["buttons"]));
+
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", out);
+    assert!(lines[0].starts_with("ADDRESS"), "{}", out);
+    assert!(lines[1].starts_with("80:e4:da:71:12:34"), "{}", out);
+    assert!(lines[1].contains("white"), "{}", out);
+    assert!(lines[1].ends_with('-'), "{}", out);
+}
+
+#[test]
+fn buttons_without_verified_buttons() {
+    let fake = FakeFlicd::start().unwrap();
+    respond_with_info(&fake, vec![]);
+
+    assert_eq!(stdout(&run(&fake, &["buttons"])), "No verified buttons.\n");
+}
+
+#[test]
+fn delete_sends_delete_button() {
+    let fake = FakeFlicd::start().unwrap();
+    fake.respond_to(Opcode::DeleteButton, |cmd| match cmd {
+        AnyCommand::DeleteButton(cmd) => vec![Event::ButtonDeleted(ButtonDeleted {
+            bd_addr: cmd.bd_addr,
+            deleted_by_this_client: true,
+        })],
+        _ => vec![],
+    });
+
+    let out = stdout(&run(&fake, &["delete", "--yes", "80:e4:da:71:12:34"]));
+
+    assert_eq!(out, "Deleted 80:e4:da:71:12:34.\n");
+    match fake.wait_for_command(Opcode::DeleteButton, TIMEOUT) {
+        Some(AnyCommand::DeleteButton(cmd)) => assert_eq!(cmd.bd_addr, bd_addr()),
+        cmd => panic!("expected DeleteButton, got {:?}", cmd),
+    }
+}
+
+#[test]
+fn disconnect_force_disconnects() {
+    let fake = FakeFlicd::start().unwrap();
+    // ForceDisconnect only names the button, so remember the channel the cli made for it.
+    let conn_id = Arc::new(AtomicU32::new(0));
+    let c = Arc::clone(&conn_id);
+    fake.respond_to(Opcode::CreateConnectionChannel, move |cmd| match cmd {
+        AnyCommand::CreateConnectionChannel(cmd) => {
+            c.store(cmd.conn_id, Ordering::SeqCst);
+            vec![channel_created(cmd.conn_id)]
+        }
+        _ => vec![],
+    });
+    fake.respond_to(Opcode::ForceDisconnect, move |_| {
+        vec![Event::ConnectionChannelRemoved(ConnectionChannelRemoved {
+            conn_id: conn_id.load(Ordering::SeqCst),
+            removed_reason: RemovedReason::ForceDisconnectedByThisClient,
+        })]
+    });
+
+    let out = stdout(&run(&fake, &["disconnect", "80:e4:da:71:12:34"]));
+
+    assert_eq!(out, "Disconnected 80:e4:da:71:12:34.\n");
+    match fake.wait_for_command(Opcode::ForceDisconnect, TIMEOUT) {
+        Some(AnyCommand::ForceDisconnect(cmd)) => assert_eq!(cmd.bd_addr, bd_addr()),
+        cmd => panic!("expected ForceDisconnect, got {:?}", cmd),
+    }
+}
+
+#[test]
+fn battery_reports_each_button() {
+    let fake = FakeFlicd::start().unwrap();
+    respond_with_info(&fake, vec![bd_addr()]);
+    fake.respond_to(Opcode::CreateBatteryStatusListener, |cmd| match cmd {
+        AnyCommand::CreateBatteryStatusListener(cmd) => {
+            vec![Event::BatteryStatus(BatteryStatus {
+                listener_id: cmd.listener_id,
+                battery_percentage: 87,
+                timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            })]
+        }
+        _ => vec![],
+    });
+
+    let out = stdout(&run(&fake, &["battery"]));
+
+    assert!(
+        out.starts_with("80:e4:da:71:12:34: 87%, as of 2020-09-13T12:26:40Z ("),
+        "{}",
+        out
+    );
+    assert!(fake
+        .wait_for_command(Opcode::RemoveBatteryStatusListener, TIMEOUT)
+        .is_some());
+}
+
+// Makes the fake run through the whole scan wizard as soon as it's created, finishing with result.
+fn run_wizard(fake: &FakeFlicd, result: ScanWizardResult) {
+    fake.respond_to(Opcode::CreateScanWizard, move |cmd| {
+        let scan_wizard_id = match cmd {
+            AnyCommand::CreateScanWizard(cmd) => cmd.scan_wizard_id,
+            _ => return vec![],
+        };
+        vec![
+            Event::ScanWizardFoundPublicButton(ScanWizardFoundPublicButton {
+                scan_wizard_id,
+                bd_addr: bd_addr(),
+                name: String::from("F022Ph"),
+            }),
+            Event::ScanWizardButtonConnected(ScanWizardButtonConnected { scan_wizard_id }),
+            Event::ScanWizardCompleted(ScanWizardCompleted {
+                scan_wizard_id,
+                result,
+            }),
+        ]
+    });
+}
+
+#[test]
+fn pair_prints_progress() {
+    let fake = FakeFlicd::start().unwrap();
+    run_wizard(&fake, ScanWizardResult::WizardSuccess);
+
+    let out = stdout(&run(&fake, &["pair"]));
+
+    assert!(
+        out.contains("Found button F022Ph (80:e4:da:71:12:34), connecting...\n"),
+        "{}",
+        out
+    );
+    assert!(
+        out.ends_with("Paired button 80:e4:da:71:12:34.\n"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn pair_exit_status_says_why_it_failed() {
+    let fake = FakeFlicd::start().unwrap();
+    run_wizard(&fake, ScanWizardResult::WizardFailedTimeout);
+
+    let out = run(&fake, &["pair"]);
+
+    assert_eq!(out.status.code(), Some(12));
+    assert_eq!(
+        String::from_utf8_lossy(&out.stderr),
+        "Pairing failed: timed out waiting for the button\n"
+    );
+}
+
+#[test]
+fn watch_prints_events() {
+    let fake = FakeFlicd::start().unwrap();
+    fake.respond_to(Opcode::CreateConnectionChannel, |cmd| match cmd {
+        AnyCommand::CreateConnectionChannel(cmd) => vec![channel_created(cmd.conn_id)],
+        _ => vec![],
+    });
+    let mut child = cli(&fake)
+        .args(["watch", "80:e4:da:71:12:34"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let conn_id = match fake.wait_for_command(Opcode::CreateConnectionChannel, TIMEOUT) {
+        Some(AnyCommand::CreateConnectionChannel(cmd)) => cmd.conn_id,
+        cmd => panic!("expected CreateConnectionChannel, got {:?}", cmd),
+    };
+    // The battery listener is created last, so by then watch knows the channel is ours.
+    assert!(fake
+        .wait_for_command(Opcode::CreateBatteryStatusListener, TIMEOUT)
+        .is_some());
+    fake.send_event(&button_down(conn_id)).unwrap();
+
+    let mut line = String::new();
+    let read = BufReader::new(child.stdout.take().unwrap()).read_line(&mut line);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    read.unwrap();
+    assert_eq!(line, "80:e4:da:71:12:34: ButtonDown\n");
+}
@@ -0,0 +1,131 @@
+// Runs the hub binary against a FakeFlicd, checking that it connects to the buttons in its
+// registry, keeps the registry up to date and runs the rules for their clicks.
+
+use flic::commands::{AnyCommand, Opcode};
+use flic::enums::{
+    BdAddrType, BluetoothControllerState, ConnectionStatus, CreateConnectionChannelError,
+};
+use flic::events::{
+    BatteryStatus, CreateConnectionChannelResponse, Event, GetButtonInfoResponse, GetInfoResponse,
+};
+use flic::testing::{bd_addr, button_down, temp_path, FakeFlicd, TIMEOUT};
+use flic::{BdAddr, Uuid};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Answers everything the hub asks flicd for at startup, as if bd_addr() were paired and in range.
+fn respond_as_flicd(fake: &FakeFlicd) {
+    fake.respond_to(Opcode::GetInfo, |_| {
+        vec![Event::GetInfoResponse(GetInfoResponse {
+            bluetooth_controller_state: BluetoothControllerState::Attached,
+            my_bd_addr: BdAddr::from_bytes([0x06, 0x05, 0x04, 0x03, 0x02, 0x01]),
+            my_bd_addr_type: BdAddrType::PublicBdAddrType,
+            max_pending_connections: 15,
+            max_concurrently_connected_buttons: -1,
+            current_pending_connections: 0,
+            currently_no_space_for_new_connection: false,
+            nb_verified_buttons: 1,
+            bd_addr_of_verified_buttons: vec![bd_addr()],
+        })]
+    });
+    fake.respond_to(Opcode::GetButtonInfo, |cmd| match cmd {
+        AnyCommand::GetButtonInfo(cmd) => {
+            vec![Event::GetButtonInfoResponse(GetButtonInfoResponse {
+                bd_addr: cmd.bd_addr,
+                uuid: Uuid::from_bytes([0; 16]),
+                color: String::from("white"),
+                serial_number: String::from("AA00-A00000"),
+            })]
+        }
+        _ => vec![],
+    });
+    fake.respond_to(Opcode::CreateConnectionChannel, |cmd| match cmd {
+        AnyCommand::CreateConnectionChannel(cmd) => {
+            vec![Event::CreateConnectionChannelResponse(
+                CreateConnectionChannelResponse {
+                    conn_id: cmd.conn_id,
+                    error: CreateConnectionChannelError::NoError,
+                    connection_status: ConnectionStatus::Ready,
+                },
+            )]
+        }
+        _ => vec![],
+    });
+    fake.respond_to(Opcode::CreateBatteryStatusListener, |cmd| match cmd {
+        AnyCommand::CreateBatteryStatusListener(cmd) => {
+            vec![Event::BatteryStatus(BatteryStatus {
+                listener_id: cmd.listener_id,
+                battery_percentage: 87,
+                timestamp: SystemTime::now(),
+            })]
+        }
+        _ => vec![],
+    });
+}
+
+// Waits for the file at path to contain text, returning what it last held.
+fn wait_for_text(path: &Path, text: &str) -> String {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let data = fs::read_to_string(path).unwrap_or_default();
+        if data.contains(text) || Instant::now() >= deadline {
+            return data;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn runs_rules_for_registered_buttons() {
+    let registry = temp_path();
+    let rules = temp_path();
+    let clicks = temp_path();
+    fs::write(&registry, "[80:e4:da:71:12:34]\nnickname = Kitchen\n").unwrap();
+    fs::write(
+        &rules,
+        format!("[Kitchen]\ndown = file {}\n", clicks.display()),
+    )
+    .unwrap();
+
+    let fake = FakeFlicd::start().unwrap();
+    respond_as_flicd(&fake);
+    let mut hub = Command::new(env!("CARGO_BIN_EXE_hub"))
+        .arg("--flicd_addr")
+        .arg(fake.addr())
+        .arg(&registry)
+        .arg(&rules)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // The rules are hooked up by the time the hub says how many buttons it connected to.
+    let mut stdout = BufReader::new(hub.stdout.take().unwrap()).lines();
+    let connected = stdout
+        .by_ref()
+        .map(|line| line.unwrap())
+        .find(|line| line.starts_with("Connected to"));
+    let conn_id = match fake.wait_for_command(Opcode::CreateConnectionChannel, TIMEOUT) {
+        Some(AnyCommand::CreateConnectionChannel(cmd)) => cmd.conn_id,
+        cmd => panic!("expected CreateConnectionChannel, got {:?}", cmd),
+    };
+    fake.send_event(&button_down(conn_id)).unwrap();
+    let clicked = wait_for_text(&clicks, "down");
+    let saved = wait_for_text(&registry, "battery_level");
+
+    hub.kill().unwrap();
+    hub.wait().unwrap();
+    for path in [&registry, &rules, &clicks] {
+        let _ = fs::remove_file(path);
+    }
+
+    assert_eq!(connected.as_deref(), Some("Connected to 1 of 1 buttons"));
+    assert_eq!(clicked, "80:e4:da:71:12:34 down\n");
+    assert!(saved.contains("nickname = Kitchen\n"), "{}", saved);
+    assert!(saved.contains("color = white\n"), "{}", saved);
+    assert!(saved.contains("serial_number = AA00-A00000\n"), "{}", saved);
+    assert!(saved.contains("battery_level = 87\n"), "{}", saved);
+}